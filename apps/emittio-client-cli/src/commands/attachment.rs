@@ -0,0 +1,32 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use emittio_inbox::Message;
+
+use crate::{AppState, AttachmentArgs, AttachmentCmd, commands::pull::message_path};
+
+pub async fn handle(app: &mut AppState, args: AttachmentArgs) -> Result<()> {
+    let client = app.client.as_mut().context("client not initialized")?;
+
+    match args.command {
+        AttachmentCmd::Get { inbox, message, name, out } => {
+            let bytes = std::fs::read(message_path(&app.dir, &message)).context("message not pulled")?;
+            let message: Message = postcard::from_bytes(&bytes)?;
+
+            let inbox = client.use_inbox(&inbox);
+            let mut reader = inbox.get_attachment(message, name.clone()).await??;
+
+            // The name comes from the sender, so it must not point outside the current directory
+            let out = match out {
+                Some(out) => out,
+                None => Path::new(&name).file_name().map(PathBuf::from).context("invalid attachment name")?,
+            };
+            let mut file = tokio::fs::File::create(&out).await?;
+            tokio::io::copy(&mut reader, &mut file).await?;
+
+            println!("Saved to {}.", out.display());
+        },
+    }
+
+    Ok(())
+}
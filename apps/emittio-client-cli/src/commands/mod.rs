@@ -1,3 +1,6 @@
 pub mod seed;
+pub mod peer;
 pub mod send;
 pub mod pull;
+pub mod read;
+pub mod attachment;
\ No newline at end of file
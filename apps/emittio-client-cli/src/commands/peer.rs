@@ -0,0 +1,56 @@
+use std::{io::ErrorKind, path::Path};
+
+use anyhow::{Result, ensure};
+use emittio_network::{peer::Peer, reputation::SavedScores};
+
+use crate::{AppState, PeerArgs, PeerCmd};
+
+/// Bootstrap peers are kept here, one file per peer
+pub const PEERS_DIR: &str = "peers";
+/// Reputation of peers, kept between runs
+pub const SCORES_FILE: &str = "scores";
+
+pub fn handle(app: &mut AppState, args: PeerArgs) -> Result<()> {
+    match args.command {
+        PeerCmd::Add { file } => {
+            let peer: Peer = postcard::from_bytes(&std::fs::read(file)?)?;
+            ensure!(peer.pk.id() == peer.id, "peer id doesn't match its key");
+
+            std::fs::create_dir_all(app.dir.join(PEERS_DIR))?;
+            std::fs::write(app.dir.join(PEERS_DIR).join(peer.id.to_string()), postcard::to_stdvec(&peer)?)?;
+
+            println!("Peer {} at {} added.", peer.id, peer.address);
+        },
+    }
+
+    Ok(())
+}
+
+/// Bootstrap peers added with `peer add`
+pub fn load(dir: &Path) -> Result<Vec<Peer>> {
+    let entries = match std::fs::read_dir(dir.join(PEERS_DIR)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    entries
+        .map(|entry| Ok(postcard::from_bytes(&std::fs::read(entry?.path())?)?))
+        .collect()
+}
+
+/// Peer scores saved by the last run, if any
+pub fn load_scores(dir: &Path) -> Result<Option<SavedScores>> {
+    match std::fs::read(dir.join(SCORES_FILE)) {
+        Ok(bytes) => Ok(Some(postcard::from_bytes(&bytes)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn save_scores(dir: &Path, saved: &SavedScores) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join(SCORES_FILE), postcard::to_stdvec(saved)?)?;
+
+    Ok(())
+}
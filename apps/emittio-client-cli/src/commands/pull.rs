@@ -1,14 +1,25 @@
+use std::path::{Path, PathBuf};
+
 use anyhow::{Context, Result};
 use emittio_crypto::id::Id;
 use emittio_inbox::Message;
 
 use crate::{AppState, PullArgs};
 
+/// Pulled messages are kept here, so their attachments can be downloaded later
+pub const MESSAGES_DIR: &str = "messages";
+
 struct MessagePrinter<'a>(&'a Id, &'a Message);
 
 impl<'a> MessagePrinter<'a> {
     fn display(&self) -> String {
-        format!("{}@emittio | {} | Message {}", Id::hash_from(&self.1.from).expect("failed to hash object"), self.1.subject, self.0)
+        let mut line = format!("{}@emittio | {} | Message {}", Id::hash_from(&self.1.from).expect("failed to hash object"), self.1.subject, self.0);
+
+        for attachment in self.1.attachments.iter() {
+            line += &format!("\n    {} ({}, {} bytes)", attachment.name, attachment.mime, attachment.size);
+        }
+
+        line
     }
 }
 
@@ -18,15 +29,22 @@ pub async fn handle(app: &mut AppState, args: PullArgs) -> Result<()> {
     let inbox_name = args.inbox;
     let inbox = client.use_inbox(&inbox_name);
 
-    let res = inbox.pull().await?;
+    let res = inbox.pull().await??;
 
     if res.len() == 0 {
         println!("No new messages.");
     } else {
+        std::fs::create_dir_all(app.dir.join(MESSAGES_DIR))?;
+
         for message_entry in res {
+            std::fs::write(message_path(&app.dir, &message_entry.0.to_string()), postcard::to_stdvec(&message_entry.1)?)?;
             println!("{}", MessagePrinter(&message_entry.0, &message_entry.1).display());
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn message_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(MESSAGES_DIR).join(id)
+}
@@ -0,0 +1,18 @@
+use anyhow::{Context, Result};
+use emittio_inbox::Message;
+
+use crate::{AppState, ReadArgs, commands::pull::message_path};
+
+pub async fn handle(app: &mut AppState, args: ReadArgs) -> Result<()> {
+    let client = app.client.as_mut().context("client not initialized")?;
+
+    let bytes = std::fs::read(message_path(&app.dir, &args.message)).context("message not pulled")?;
+    let message: Message = postcard::from_bytes(&bytes)?;
+
+    let inbox = client.use_inbox(&args.inbox);
+    let mut reader = inbox.get_text(message).await?;
+
+    tokio::io::copy(&mut reader, &mut tokio::io::stdout()).await?;
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
-use std::io::Read;
+use std::{io::Read, path::Path};
 
 use anyhow::{Context, Result};
+use emittio_inbox::AttachmentSource;
 use tokio::io::AsyncRead;
 
 use crate::{AppState, SendArgs};
@@ -27,9 +28,41 @@ pub async fn handle(app: &mut AppState, args: SendArgs) -> Result<()> {
         Box::new(std::io::Cursor::new(body_string))
     };
 
-    inbox.send(args.subject, recipient_address, body).await?;
+    let attachments = args.attach.iter()
+        .map(|path| attachment_source(path))
+        .collect::<Result<_>>()?;
 
-    println!("Message send.");
+    let cid = inbox.send(args.subject, recipient_address, body, attachments).await??;
+
+    println!("Message {cid} sent.");
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn attachment_source(path: &Path) -> Result<AttachmentSource> {
+    let file = std::fs::File::open(path)?;
+
+    Ok(AttachmentSource {
+        name: path.file_name().context("attachment has no file name")?.to_string_lossy().into_owned(),
+        mime: mime_type(path).to_string(),
+        size: file.metadata()?.len(),
+        reader: Box::new(tokio::fs::File::from_std(file)),
+    })
+}
+
+/// Guesses the MIME type from the file extension
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("txt") => "text/plain",
+        Some("html" | "htm") => "text/html",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
@@ -6,7 +6,7 @@ use clap::{Parser, Subcommand};
 use emittio_client::Client;
 use directories::ProjectDirs;
 
-use crate::commands::{pull, seed::{self, SEED_FILE}, send};
+use crate::commands::{attachment, peer, pull, read, seed::{self, SEED_FILE}, send};
 
 const APP_NAME: &str = "emittio";
 
@@ -20,8 +20,11 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     Seed(SeedArgs),
+    Peer(PeerArgs),
     Send(SendArgs),
     Pull(PullArgs),
+    Read(ReadArgs),
+    Attachment(AttachmentArgs),
 }
 
 #[derive(Parser)]
@@ -39,6 +42,22 @@ enum SeedCmd {
     },
 }
 
+#[derive(Parser)]
+struct PeerArgs {
+    #[command(subcommand)]
+    command: PeerCmd,
+}
+
+#[derive(Subcommand)]
+enum PeerCmd {
+    /// Adds a bootstrap peer. Every command starts by connecting to the added peers
+    Add {
+        /// Encoded `Peer` of a node
+        #[arg(long)]
+        file: PathBuf,
+    },
+}
+
 #[derive(Parser)]
 struct SendArgs {
     #[arg(long)]
@@ -58,6 +77,10 @@ struct SendArgs {
 
     #[arg(long, conflicts_with_all = ["body", "body_file"])]
     edit: bool,
+
+    /// File to attach, can be repeated
+    #[arg(long)]
+    attach: Vec<PathBuf>,
 }
 
 #[derive(Parser)]
@@ -66,6 +89,43 @@ struct PullArgs {
     inbox: String,
 }
 
+/// Prints the body of a pulled message
+#[derive(Parser)]
+struct ReadArgs {
+    #[arg(long)]
+    inbox: String,
+
+    /// Id of the message as printed by `pull`
+    #[arg(long)]
+    message: String,
+}
+
+#[derive(Parser)]
+struct AttachmentArgs {
+    #[command(subcommand)]
+    command: AttachmentCmd,
+}
+
+#[derive(Subcommand)]
+enum AttachmentCmd {
+    /// Downloads an attachment of a pulled message
+    Get {
+        #[arg(long)]
+        inbox: String,
+
+        /// Id of the message as printed by `pull`
+        #[arg(long)]
+        message: String,
+
+        #[arg(long)]
+        name: String,
+
+        /// Where to save the attachment, defaults to its name
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
 struct AppState {
     client: Option<Client>,
     dir: PathBuf,
@@ -90,12 +150,27 @@ async fn main() -> anyhow::Result<()> {
         None
     };
 
+    if let Some(client) = &client {
+        client.bootstrap(peer::load(&app_dir)?).await?;
+
+        if let Some(saved) = peer::load_scores(&app_dir)? {
+            client.restore_scores(saved).await?;
+        }
+    }
+
     let mut app = AppState::new(client, app_dir);
 
     match cli.command {
         Command::Seed(args) => seed::handle(&mut app, args)?,
+        Command::Peer(args) => peer::handle(&mut app, args)?,
         Command::Send(args) => send::handle(&mut app, args).await?,
         Command::Pull(args) => pull::handle(&mut app, args).await?,
+        Command::Read(args) => read::handle(&mut app, args).await?,
+        Command::Attachment(args) => attachment::handle(&mut app, args).await?,
+    }
+
+    if let Some(client) = &app.client {
+        peer::save_scores(&app.dir, &client.saved_scores().await?)?;
     }
 
     Ok(())
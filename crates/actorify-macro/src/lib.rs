@@ -1,14 +1,20 @@
 use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use quote::{ToTokens, format_ident, quote};
-use syn::{Attribute, FnArg, ImplItem, ItemImpl, Meta, Pat, parse_macro_input, parse_quote};
+use syn::{Attribute, FnArg, ImplItem, ItemImpl, Meta, Pat, Type, parse_macro_input, parse_quote};
 
 #[proc_macro_attribute]
 pub fn actor(_args: TokenStream, item: TokenStream) -> TokenStream {
     let mut item = parse_macro_input!(item as ItemImpl);
 
-    let actor_ty = item.self_ty.as_ref();
-    let actor_name = actor_ty.to_token_stream().to_string();
+    let actor_ty = item.self_ty.clone();
+    // Generic parameters stay on the actor only: commands must not mention them, so the handle is the same for every instantiation
+    let actor_name = match actor_ty.as_ref() {
+        Type::Path(path) => path.path.segments.last().expect("Empty actor type").ident.to_string(),
+        other => other.to_token_stream().to_string(),
+    };
+    let generics = item.generics.clone();
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     let cmd_ident = format_ident!("{}Cmd", &actor_name);
     let handle_ident = format_ident!("{}Handle", &actor_name);
 
@@ -84,7 +90,7 @@ pub fn actor(_args: TokenStream, item: TokenStream) -> TokenStream {
                     });
                 } else if let Some(attr) = find_and_remove_attr(&mut impl_item_fn.attrs, "listen") {
                     let fn_name = &impl_item_fn.sig.ident;
-                    let source = attr.to_token_stream();
+                    let source = attr_args(&attr);
 
                     actor_listener_arms.push(quote! {
                         Some(value) = #source => {
@@ -94,7 +100,7 @@ pub fn actor(_args: TokenStream, item: TokenStream) -> TokenStream {
                 } else if let Some(attr) = find_and_remove_attr(&mut impl_item_fn.attrs, "every") {
                     let fn_name = &impl_item_fn.sig.ident;
                     let timer_ident = format_ident!("{}_timer", fn_name);
-                    let frequency = attr.to_token_stream();
+                    let frequency = attr_args(&attr);
 
                     actor_timer_inits.push(quote! {
                         let mut #timer_ident = ::actorify::tokio::time::interval(#frequency);
@@ -124,7 +130,7 @@ pub fn actor(_args: TokenStream, item: TokenStream) -> TokenStream {
 
         #item
 
-        impl ::actorify::Actor for #actor_ty {
+        impl #impl_generics ::actorify::Actor for #actor_ty #where_clause {
             type Handle = #handle_ident;
             fn run(mut self, token: ::actorify::tokio_util::sync::CancellationToken) -> (Self::Handle, impl Future<Output = ()> + Send) {
                 let (tx, mut rx) = ::actorify::Channel::new(1024);
@@ -165,6 +171,14 @@ fn find_and_remove_attr(
     Some(attrs.remove(index))
 }
 
+fn attr_args(attr: &Attribute) -> proc_macro2::TokenStream {
+    attr.meta
+        .require_list()
+        .expect("Attribute requires arguments")
+        .tokens
+        .clone()
+}
+
 fn extract_docs(attrs: &[Attribute]) -> String {
     attrs
         .iter()
@@ -1,8 +1,8 @@
 use std::collections::HashMap;
-use actorify::{tokio_util::sync::CancellationToken, Actor, ActorJoinMap};
+use actorify::{tokio_util::sync::CancellationToken, Actor, ActorJoinMap, ChannelError};
 use emittio_crypto::{OsRng, RngCore, blake3, derivable::Derivable, kem::Kem, tag::TagVerifier};
-use emittio_inbox::{InboxActor, InboxActorHandle};
-use emittio_network::actor::{NetworkActorHandle, NetworkActor};
+use emittio_inbox::{BucketPrecision, InboxActor, InboxActorHandle};
+use emittio_network::{actor::{NetworkActorHandle, NetworkActor}, peer::Peer, reputation::SavedScores, tcp::TcpTransport};
 
 type InboxId = [u8; 32];
 
@@ -24,7 +24,7 @@ impl Client {
 
     #[inline]
     pub fn from_seed(seed: [u8; 32]) -> Self {
-        let handle = NetworkActor::new().spawn();
+        let handle = NetworkActor::new(TcpTransport, None).spawn();
 
         Self {
             seed,
@@ -34,13 +34,38 @@ impl Client {
         }
     }
 
+    /// Seeds the routing table with known peers. Clients find every other peer through them
+    pub async fn bootstrap(&self, peers: Vec<Peer>) -> Result<(), ChannelError> {
+        for peer in peers {
+            self.network.add_peer(peer).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reputation of the peers the client has talked to, to be kept across restarts
+    pub async fn saved_scores(&self) -> Result<SavedScores, ChannelError> {
+        self.network.saved_scores().await
+    }
+
+    /// Restores scores saved by an earlier run, so peers caught lying stay distrusted
+    pub async fn restore_scores(&self, saved: SavedScores) -> Result<(), ChannelError> {
+        self.network.restore_scores(saved).await
+    }
+
     pub fn use_inbox(&mut self, name: &str) -> &InboxActorHandle {
+        self.use_inbox_with(name, BucketPrecision::default())
+    }
+
+    /// Like `use_inbox`, but with a bucket precision other than the default. It only applies when the inbox is first used
+    pub fn use_inbox_with(&mut self, name: &str, precision: BucketPrecision) -> &InboxActorHandle {
         let inbox_id: InboxId = blake3::derive_key(INBOX_CTX, name.as_bytes()).into();
 
         let inbox = self.inboxes
             .entry(inbox_id)
             .or_insert_with(|| {
                 let (handle, actor_future) = InboxActor::new(self.network.clone(), Kem::derive_with_info(self.seed, &inbox_id), TagVerifier::derive_with_info(self.seed, &inbox_id))
+                    .with_precision(precision)
                     .run(CancellationToken::new());
                 
                 self.inbox_actors.spawn(inbox_id, actor_future);
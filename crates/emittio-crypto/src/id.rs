@@ -41,7 +41,7 @@ impl fmt::Display for Id {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Mask(pub [u8; 32]);
 
 impl Mask {
@@ -31,6 +31,15 @@ impl TagVerifier {
     }
 }
 
+impl Tag {
+    /// Whether both points are on the curve and not of small order. `generate_tag` never produces such points,
+    /// and a tag made of identities would match every verifier
+    pub fn is_valid(&self) -> bool {
+        [&self.pk, &self.shared].into_iter()
+            .all(|point| point.to_edwards(0).is_some_and(|point| !point.is_small_order()))
+    }
+}
+
 impl TagAddress {
     pub fn generate_tag(&self) -> Tag {
         let sk = Scalar::random(&mut OsRng);
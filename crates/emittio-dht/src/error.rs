@@ -1,12 +1,25 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum DhtGetError {
     #[error("Internal error")]
     Internal,
+
+    /// The node doesn't store the content
+    #[error("Not found")]
+    NotFound,
+
+    /// The stored content doesn't match its cid
+    #[error("Content corrupted")]
+    Corrupted,
+
+    /// The node is busy, asking it later may succeed
+    #[error("Rate limited")]
+    RateLimited,
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, Serialize, Deserialize)]
 pub enum DhtPutError {
     #[error("File too large")]
     TooLarge,
@@ -3,5 +3,12 @@ pub mod types;
 #[cfg(feature = "node")]
 pub mod service;
 pub mod error;
+pub mod replication;
 
-pub const DHT_SERVICE_ID: u16 = 2;
\ No newline at end of file
+use emittio_network::queries;
+
+use crate::query::{DhtGet, DhtHas, DhtPut};
+
+pub const DHT_SERVICE_ID: u16 = 2;
+
+queries!(pub QUERIES = [DhtGet, DhtPut, DhtHas]);
\ No newline at end of file
@@ -1,27 +1,69 @@
 use bytes::Bytes;
 use emittio_crypto::id::Id;
-use emittio_network::types::{PeerSelection, PowConfig, IntoQuery, RouteConfig, VerificationMethod};
+use emittio_network::{pow::PowConfig, query::{PeerSelection, Queryable}, reply::ReplyStatus, verifier::{HashVerifier, VerificationInput, VerificationOutput, Verifier}};
 use serde::{Deserialize, Serialize};
 
 use crate::{DHT_SERVICE_ID, error::{DhtGetError, DhtPutError}};
 
-const REPLICATION: u16 = 5;
+/// How many of the closest peers store each blob
+pub const REPLICATION: u16 = 5;
+/// Largest blob nodes accept with `DhtPut`
+pub const MAX_LEN: usize = 256 * 1000;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct DhtGet {
     pub cid: Id,
 }
 
-impl IntoQuery for DhtGet {
+impl Queryable for DhtGet {
     const SERVICE_ID: u16 = DHT_SERVICE_ID;
     const METHOD_ID: u16 = 1;
+
     type Reply = Result<Bytes, DhtGetError>;
 
-    fn route_config(&self) -> RouteConfig {
-        RouteConfig { peers: PeerSelection::Closest { target: self.cid.clone(), count: REPLICATION }, pow: PowConfig::High }
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Closest { target: self.cid, count: REPLICATION }
+    }
+
+    fn verifier(&self) -> impl Verifier<Self::Reply> {
+        DhtGetVerifier(HashVerifier(self.cid))
+    }
+
+    fn pow(&self) -> PowConfig {
+        PowConfig::High
     }
-    fn verification_method(&self) -> VerificationMethod {
-        VerificationMethod::Hash(self.cid.clone())
+
+    fn status_reply(status: ReplyStatus) -> Option<Self::Reply> {
+        (status == ReplyStatus::RateLimited).then_some(Err(DhtGetError::RateLimited))
+    }
+}
+
+/// Checks the hash of found content. Nodes that don't have the content or are busy aren't penalized,
+/// nodes that fail to serve it are. If every node that answered is busy, the reply is `RateLimited`
+pub struct DhtGetVerifier(pub HashVerifier);
+
+impl Verifier<Result<Bytes, DhtGetError>> for DhtGetVerifier {
+    fn verify(&self, replies: VerificationInput<Result<Bytes, DhtGetError>>) -> VerificationOutput<Result<Bytes, DhtGetError>> {
+        let mut results = Vec::new();
+        let mut found = Vec::new();
+        let busy = !replies.is_empty() && replies.iter().all(|(_, reply)| *reply == Err(DhtGetError::RateLimited));
+
+        for (id, reply) in replies {
+            match reply {
+                Ok(bytes) => found.push((id, bytes)),
+                Err(DhtGetError::NotFound | DhtGetError::RateLimited) => {},
+                Err(DhtGetError::Corrupted | DhtGetError::Internal) => results.push((id, false)),
+            }
+        }
+
+        if busy {
+            return (results, Some(Err(DhtGetError::RateLimited)));
+        }
+
+        let (verified, reply) = self.0.verify(found);
+        results.extend(verified);
+
+        (results, reply.map(Ok))
     }
 }
 
@@ -30,12 +72,38 @@ pub struct DhtPut {
     pub bytes: Bytes,
 }
 
-impl IntoQuery for DhtPut {
+impl Queryable for DhtPut {
     const SERVICE_ID: u16 = DHT_SERVICE_ID;
     const METHOD_ID: u16 = 2;
+
     type Reply = Result<(), DhtPutError>;
 
-    fn route_config(&self) -> RouteConfig {
-        RouteConfig { peers: PeerSelection::Closest { target: Id::hash_bytes(&self.bytes), count: REPLICATION }, pow: PowConfig::High }
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Closest { target: Id::hash_bytes(&self.bytes), count: REPLICATION }
+    }
+
+    fn pow(&self) -> PowConfig {
+        PowConfig::High
+    }
+}
+
+/// Asks whether a peer stores the content. Used to find replicas that need to be restored
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DhtHas {
+    pub cid: Id,
+}
+
+impl Queryable for DhtHas {
+    const SERVICE_ID: u16 = DHT_SERVICE_ID;
+    const METHOD_ID: u16 = 3;
+
+    type Reply = bool;
+
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Closest { target: self.cid, count: REPLICATION }
+    }
+
+    fn pow(&self) -> PowConfig {
+        PowConfig::Low
     }
 }
@@ -0,0 +1,33 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use emittio_crypto::id::Id;
+use emittio_network::{actor::NetworkActorHandle, error::NetworkError, lookup::lookup, peer::{Peer, PeerId}, query::Queryable};
+
+use crate::query::{DhtHas, DhtPut, REPLICATION};
+
+/// Makes sure the `REPLICATION` closest peers store the content, putting it to those that lost it or never had it.
+/// Returns how many of them store it afterwards.
+///
+/// Storage nodes run it for their blobs to repair replicas after churn. Clients run it for their own messages,
+/// so they stay available while the recipient is offline
+pub async fn republish(network: &NetworkActorHandle, bytes: Bytes) -> Result<usize, NetworkError> {
+    let cid = Id::hash_bytes(&bytes);
+    let peers = lookup(network, cid, REPLICATION as usize).await?;
+
+    let stored: HashSet<PeerId> = DhtHas { cid }.query_each(network, peers.clone()).await?
+        .into_iter()
+        .filter_map(|(peer_id, has)| has.then_some(peer_id))
+        .collect();
+
+    let missing: Vec<Peer> = peers.into_iter()
+        .filter(|peer| !stored.contains(&peer.id))
+        .collect();
+
+    let put = DhtPut { bytes }.query_each(network, missing).await?
+        .into_iter()
+        .filter(|(_, reply)| reply.is_ok())
+        .count();
+
+    Ok(stored.len() + put)
+}
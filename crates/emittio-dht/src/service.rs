@@ -1,44 +1,317 @@
-use std::path::PathBuf;
+use std::{io::ErrorKind, path::{Path, PathBuf}, time::Duration};
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use emittio_crypto::id::Id;
-use emittio_network::types::Handler;
-use tokio::{fs::File, io::{self, AsyncReadExt, AsyncWriteExt}};
+use emittio_network::{actor::NetworkActorHandle, error::ServiceError, query::Queryable, service::{IncomingQuery, NetworkHandler, Service, dispatch}};
+use tokio::{fs::{self, File}, io::AsyncWriteExt, task::JoinHandle};
 
-use crate::{error::{DhtGetError, DhtPutError}, query::{DhtGet, DhtPut}};
+use crate::{error::{DhtGetError, DhtPutError}, query::{DhtGet, DhtHas, DhtPut, MAX_LEN}, replication::republish};
 
-const MAX_LEN: usize = 256 * 1000;
+/// Hex characters of the cid naming the subdirectory of a blob
+const SHARD_LEN: usize = 2;
+/// How often replicas of stored blobs are checked
+const REPAIR_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
+/// Repair of blob replicas on the closest peers
+struct Replication {
+    network: NetworkActorHandle,
+    round: Option<JoinHandle<()>>,
+}
+
+/// Content-addressed blobs, one file per cid
 pub struct DhtStorage {
     dir: PathBuf,
+    replication: Option<Replication>,
+}
+
+impl DhtStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, replication: None }
+    }
+
+    /// Republishes stored blobs through `network` on every maintenance, so they stay on enough of the closest peers
+    pub fn replicate(&mut self, network: NetworkActorHandle) {
+        self.replication = Some(Replication { network, round: None });
+    }
+}
+
+/// Republishes every intact blob in `dir` one at a time
+async fn repair_round(network: NetworkActorHandle, dir: PathBuf) -> std::io::Result<()> {
+    let mut shards = fs::read_dir(dir).await?;
+
+    while let Some(shard) = shards.next_entry().await? {
+        if !shard.file_type().await?.is_dir() {
+            continue;
+        }
+
+        let mut blobs = fs::read_dir(shard.path()).await?;
+
+        while let Some(blob) = blobs.next_entry().await? {
+            // Temporary files of interrupted writes have an extension
+            if blob.path().extension().is_some() {
+                continue;
+            }
+
+            let bytes = fs::read(blob.path()).await?;
+
+            if Id::hash_bytes(&bytes).to_string() == blob.file_name().to_string_lossy() {
+                republish(&network, bytes.into()).await.ok();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Where the content with `cid` is stored in `dir`. Blobs are sharded into subdirectories by cid prefix
+pub fn blob_path(dir: &Path, cid: &Id) -> PathBuf {
+    let name = cid.to_string();
+
+    dir.join(&name[..SHARD_LEN]).join(name)
+}
+
+/// Writes `bytes` to a temporary file and renames it to `path`, so a crash never leaves a partial blob
+async fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp).await?;
+    file.write_all(bytes).await?;
+    file.sync_all().await?;
+
+    fs::rename(tmp, path).await
 }
 
-impl Handler<DhtGet> for DhtStorage {
+impl Service for DhtStorage {
+    async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+        match incoming.query.method_id {
+            DhtGet::METHOD_ID => dispatch::<DhtGet, _>(self, &incoming).await,
+            DhtPut::METHOD_ID => dispatch::<DhtPut, _>(self, &incoming).await,
+            DhtHas::METHOD_ID => dispatch::<DhtHas, _>(self, &incoming).await,
+            method_id => Err(ServiceError::UnknownMethod(method_id)),
+        }
+    }
+
+    fn maintenance_interval(&self) -> Option<Duration> {
+        self.replication.as_ref().map(|_| REPAIR_INTERVAL)
+    }
+
+    /// Starts a repair round in the background unless the previous one is still running
+    async fn maintain(&mut self) {
+        let Some(replication) = &mut self.replication else {
+            return;
+        };
+
+        if replication.round.as_ref().is_some_and(|round| !round.is_finished()) {
+            return;
+        }
+
+        let (network, dir) = (replication.network.clone(), self.dir.clone());
+
+        replication.round = Some(tokio::spawn(async move {
+            repair_round(network, dir).await.ok();
+        }));
+    }
+}
+
+impl NetworkHandler<DhtHas> for DhtStorage {
+    /// A corrupted blob counts as missing, so republishing repairs it
+    async fn handle(&mut self, query: DhtHas) -> bool {
+        fs::read(blob_path(&self.dir, &query.cid)).await.is_ok_and(|bytes| Id::hash_bytes(&bytes) == query.cid)
+    }
+}
+
+impl NetworkHandler<DhtGet> for DhtStorage {
     async fn handle(&mut self, query: DhtGet) -> Result<Bytes, DhtGetError> {
-        let path = self.dir.join(format!("{}", query.cid));
+        let bytes = fs::read(blob_path(&self.dir, &query.cid)).await.map_err(|err| match err.kind() {
+            ErrorKind::NotFound => DhtGetError::NotFound,
+            _ => DhtGetError::Internal,
+        })?;
 
-        let mut bytes = BytesMut::new();
-        let file = File::open(path).await.map_err(|| DhtGetError::Internal)?;
-        file.read(&mut bytes).await.map_err(|| DhtGetError::Internal)?;
+        // Disk corruption must not be served as content
+        if Id::hash_bytes(&bytes) != query.cid {
+            return Err(DhtGetError::Corrupted);
+        }
 
-        Ok(bytes.freeze())
+        Ok(bytes.into())
     }
 }
 
-impl Handler<DhtPut> for DhtStorage {
+impl NetworkHandler<DhtPut> for DhtStorage {
     async fn handle(&mut self, query: DhtPut) -> Result<(), DhtPutError> {
         if query.bytes.len() > MAX_LEN {
             return Err(DhtPutError::TooLarge);
         }
 
-        let path = self.dir.join(format!("{}", Id::hash_bytes(&query.bytes)));
+        let cid = Id::hash_bytes(&query.bytes);
+        let path = blob_path(&self.dir, &cid);
 
-        let file = File::create(path).await.map_err(|| DhtPutError::Internal)?;
+        // Content is addressed by its hash, so an intact blob is the same one. A corrupted one is overwritten
+        match fs::read(&path).await {
+            Ok(bytes) if Id::hash_bytes(&bytes) == cid => return Ok(()),
+            Ok(_) => {},
+            Err(err) if err.kind() == ErrorKind::NotFound => {},
+            Err(_) => return Err(DhtPutError::Internal),
+        }
 
-        file.write(&query.bytes).await.map_err(|| DhtPutError::Internal)?;
+        let shard = path.parent().expect("blob path has a shard");
+        fs::create_dir_all(shard).await.map_err(|_| DhtPutError::Internal)?;
 
-        Ok(())
+        write_atomic(&path, &query.bytes).await.map_err(|_| DhtPutError::Internal)
     }
 }
 
-// TODO: implement service for DHT storage
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use emittio_network::{routing::distance, sim::SimNetwork};
+
+    use crate::{DHT_SERVICE_ID, query::REPLICATION};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_put_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DhtStorage::new(dir.path().into());
+
+        let bytes = Bytes::from_static(b"content");
+        let cid = Id::hash_bytes(&bytes);
+
+        NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes: bytes.clone() }).await.expect("put failed");
+        NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes: bytes.clone() }).await.expect("repeated put failed");
+
+        assert!(blob_path(dir.path(), &cid).starts_with(dir.path().join(&cid.to_string()[..SHARD_LEN])));
+        assert_eq!(NetworkHandler::<DhtGet>::handle(&mut storage, DhtGet { cid }).await.expect("get failed"), bytes);
+
+        // A blob that no longer matches its cid isn't served
+        fs::write(blob_path(dir.path(), &cid), b"corrupted").await.unwrap();
+        assert_eq!(NetworkHandler::<DhtGet>::handle(&mut storage, DhtGet { cid }).await, Err(DhtGetError::Corrupted));
+
+        // Putting the content again repairs it
+        NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes: bytes.clone() }).await.expect("repairing put failed");
+        assert_eq!(NetworkHandler::<DhtGet>::handle(&mut storage, DhtGet { cid }).await.expect("get failed"), bytes);
+    }
+
+    #[tokio::test]
+    async fn test_max_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DhtStorage::new(dir.path().into());
+
+        let bytes = Bytes::from(vec![1; MAX_LEN]);
+        NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes }).await.expect("put failed");
+
+        let bytes = Bytes::from(vec![1; MAX_LEN + 1]);
+        let cid = Id::hash_bytes(&bytes);
+
+        assert!(matches!(NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes }).await, Err(DhtPutError::TooLarge)));
+        assert!(!fs::try_exists(blob_path(dir.path(), &cid)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_missing_cid() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DhtStorage::new(dir.path().into());
+
+        let reply = NetworkHandler::<DhtGet>::handle(&mut storage, DhtGet { cid: Id::hash_bytes(b"missing") }).await;
+
+        assert_eq!(reply, Err(DhtGetError::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_get_from_next_closest() {
+        let sim = SimNetwork::new(0);
+        let bytes = Bytes::from_static(b"content");
+        let cid = Id::hash_bytes(&bytes);
+
+        let dirs: Vec<_> = (0..8).map(|_| tempfile::tempdir().unwrap()).collect();
+        let mut nodes: Vec<_> = (0..8).map(|i| sim.spawn_node(&format!("node-{i}"))).collect();
+        nodes.sort_by_key(|(_, peer)| distance(&peer.id, &cid).0);
+
+        for ((handle, _), dir) in nodes.iter().zip(dirs.iter()) {
+            for (_, peer) in nodes.iter() {
+                handle.add_peer(peer.clone()).await.expect("add peer failed");
+            }
+            handle.register(DHT_SERVICE_ID, DhtStorage::new(dir.path().into())).await.expect("register failed");
+        }
+
+        // Only the sixth closest node has the content, the closest ones reply `NotFound`
+        let mut storage = DhtStorage::new(dirs[5].path().into());
+        NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes: bytes.clone() }).await.expect("put failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(nodes[0].1.clone()).await.expect("add peer failed");
+
+        assert_eq!(DhtGet { cid }.query(&client).await.expect("query failed"), Some(Ok(bytes)));
+
+        let scores = client.scores().await.expect("scores failed");
+        assert!(scores.iter().all(|(_, peer)| peer.score >= 0.0), "peers without the content are not penalized");
+        assert!(scores.iter().any(|(peer_id, peer)| *peer_id == nodes[5].1.id && peer.score > 0.0));
+    }
+
+    /// DHT service of a node that is too busy to serve
+    struct Busy;
+
+    impl Service for Busy {
+        async fn handle(&mut self, _incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            Err(ServiceError::RateLimited)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let sim = SimNetwork::new(0);
+        let (node, peer) = sim.spawn_node("node");
+        node.register(DHT_SERVICE_ID, Busy).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        let reply = DhtGet { cid: Id::hash_bytes(b"content") }.query(&client).await.expect("query failed");
+        assert_eq!(reply, Some(Err(DhtGetError::RateLimited)));
+
+        let scores = client.scores().await.expect("scores failed");
+        assert!(scores.iter().all(|(_, peer)| peer.score >= 0.0), "busy peers are not penalized");
+    }
+
+    #[tokio::test]
+    async fn test_repair() {
+        let sim = SimNetwork::new(0);
+        let bytes = Bytes::from_static(b"content");
+        let cid = Id::hash_bytes(&bytes);
+
+        let dirs: Vec<_> = (0..8).map(|_| tempfile::tempdir().unwrap()).collect();
+        let mut nodes: Vec<_> = (0..8).map(|i| sim.spawn_node(&format!("node-{i}"))).collect();
+        nodes.sort_by_key(|(_, peer)| distance(&peer.id, &cid).0);
+
+        for ((handle, _), dir) in nodes.iter().zip(dirs.iter()) {
+            for (_, peer) in nodes.iter() {
+                handle.add_peer(peer.clone()).await.expect("add peer failed");
+            }
+            handle.register(DHT_SERVICE_ID, DhtStorage::new(dir.path().into())).await.expect("register failed");
+        }
+
+        // One of the closest nodes still has the blob, another one has a corrupted copy and the others lost it
+        let mut replica = DhtStorage::new(dirs[1].path().into());
+        NetworkHandler::<DhtPut>::handle(&mut replica, DhtPut { bytes: bytes.clone() }).await.expect("put failed");
+
+        let corrupted = blob_path(dirs[2].path(), &cid);
+        fs::create_dir_all(corrupted.parent().unwrap()).await.unwrap();
+        fs::write(corrupted, b"corrupted").await.unwrap();
+
+        let client = sim.spawn_client("client");
+        client.add_peer(nodes[0].1.clone()).await.expect("add peer failed");
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DhtStorage::new(dir.path().into());
+        NetworkHandler::<DhtPut>::handle(&mut storage, DhtPut { bytes: bytes.clone() }).await.expect("put failed");
+        storage.replicate(client);
+
+        storage.maintain().await;
+        let round = storage.replication.as_mut().and_then(|r| r.round.take()).expect("round not started");
+        round.await.expect("round failed");
+
+        for (i, dir) in dirs.iter().enumerate() {
+            let stored = fs::read(blob_path(dir.path(), &cid)).await.is_ok_and(|stored| stored == bytes);
+            assert_eq!(stored, i < REPLICATION as usize, "node {i}");
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::io;
+
+use bytes::Bytes;
+use emittio_crypto::{ciphertext::{Ciphertext, Nonce}, id::Id, kem::SharedSecret};
+use emittio_dht::query::{DhtGet, DhtPut, MAX_LEN};
+use emittio_network::{actor::NetworkActorHandle, query::Queryable};
+use futures::{StreamExt, stream};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use crate::error::InboxError;
+
+/// Plaintext bytes per chunk
+pub const CHUNK_LEN: usize = 192 * 1024;
+
+const _: () = assert!(CHUNK_LEN + 64 <= MAX_LEN, "encrypted chunks must fit into a DHT blob");
+
+/// Every chunk of a key has its own nonce
+fn nonce(index: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&index.to_le_bytes());
+    nonce
+}
+
+/// Splits `reader` into chunks encrypted under `key` and puts them to the DHT one at a time,
+/// returning their cids in order and the number of bytes read. Only one chunk is held in memory
+pub async fn put_chunks(network: &NetworkActorHandle, key: &SharedSecret, mut reader: impl AsyncRead + Unpin) -> Result<(Vec<Id>, u64), InboxError> {
+    let mut cids = Vec::new();
+    let mut total = 0;
+    let mut buf = vec![0; CHUNK_LEN];
+
+    loop {
+        let len = read_full(&mut reader, &mut buf).await?;
+        total += len as u64;
+
+        if len == 0 {
+            break;
+        }
+
+        let index = cids.len() as u64;
+        // The index is authenticated too, so chunks can't be reordered
+        let ciphertext = Ciphertext::encrypt(key, &buf[..len], nonce(index), &index.to_le_bytes())?;
+        let bytes = Bytes::from(postcard::to_stdvec(&ciphertext)?);
+        let cid = Id::hash_bytes(&bytes);
+
+        match (DhtPut { bytes }).query(network).await? {
+            Some(Ok(())) => cids.push(cid),
+            _ => return Err(InboxError::Unavailable(cid)),
+        }
+
+        if len < CHUNK_LEN {
+            break;
+        }
+    }
+
+    Ok((cids, total))
+}
+
+/// Fetches the chunk with `DhtGet`, which verifies its hash, and decrypts it
+pub async fn get_chunk(network: &NetworkActorHandle, key: &SharedSecret, index: u64, cid: Id) -> Result<Bytes, InboxError> {
+    let Some(Ok(bytes)) = DhtGet { cid }.query(network).await? else {
+        return Err(InboxError::Unavailable(cid));
+    };
+
+    let ciphertext: Ciphertext = postcard::from_bytes(&bytes)?;
+
+    Ok(ciphertext.decrypt(*key, &index.to_le_bytes())?)
+}
+
+/// Reads the chunks from byte `offset` on, fetching and decrypting each one only when the previous one has been read.
+/// Chunks before the offset aren't fetched
+pub fn read_chunks(network: NetworkActorHandle, key: SharedSecret, cids: Vec<Id>, offset: u64) -> impl AsyncRead + Send + Unpin {
+    let first = (offset / CHUNK_LEN as u64) as usize;
+    let skip = (offset % CHUNK_LEN as u64) as usize;
+
+    let chunks = stream::iter(cids.into_iter().enumerate().skip(first))
+        .then(move |(index, cid)| {
+            let network = network.clone();
+
+            async move {
+                let bytes = get_chunk(&network, &key, index as u64, cid).await.map_err(io::Error::other)?;
+
+                Ok::<_, io::Error>(match index == first {
+                    true => bytes.slice(skip.min(bytes.len())..),
+                    false => bytes,
+                })
+            }
+        });
+
+    StreamReader::new(Box::pin(chunks))
+}
+
+/// Reads until `buf` is full or the reader ends
+async fn read_full(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]).await? {
+            0 => break,
+            read => len += read,
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use emittio_dht::{DHT_SERVICE_ID, service::DhtStorage};
+    use emittio_network::sim::SimNetwork;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chunks() {
+        let sim = SimNetwork::new(0);
+        let dirs: Vec<_> = (0..2).map(|_| tempfile::tempdir().unwrap()).collect();
+        let client = sim.spawn_client("client");
+
+        for (i, dir) in dirs.iter().enumerate() {
+            let (node, peer) = sim.spawn_node(&format!("node-{i}"));
+            node.register(DHT_SERVICE_ID, DhtStorage::new(dir.path().into())).await.expect("register failed");
+            client.add_peer(peer).await.expect("add peer failed");
+        }
+
+        let body: Vec<u8> = (0..CHUNK_LEN + 100).map(|i| i as u8).collect();
+        let key = [7; 32];
+
+        let (cids, len) = put_chunks(&client, &key, body.as_slice()).await.expect("put failed");
+        assert_eq!((cids.len(), len), (2, body.len() as u64));
+
+        let mut read = Vec::new();
+        read_chunks(client.clone(), key, cids.clone(), 0).read_to_end(&mut read).await.expect("read failed");
+        assert_eq!(read, body);
+
+        // Reading from an offset in the second chunk doesn't need the first one
+        let offset = CHUNK_LEN as u64 + 10;
+        let mut read = Vec::new();
+        read_chunks(client.clone(), key, vec![Id::default(), cids[1]], offset).read_to_end(&mut read).await.expect("read failed");
+        assert_eq!(read, body[offset as usize..]);
+
+        // Chunks are bound to their position
+        let swapped = vec![cids[1], cids[0]];
+        assert!(read_chunks(client.clone(), key, swapped, 0).read_to_end(&mut Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,35 @@
+use emittio_crypto::{error::CryptoError, id::Id};
+use emittio_network::error::NetworkError;
+use emittio_pointer::error::PutPointerError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InboxError {
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+
+    #[error(transparent)]
+    Postcard(#[from] postcard::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Nodes of the bucket refused the pointer
+    #[error(transparent)]
+    Pointer(#[from] PutPointerError),
+
+    /// No peer answered a query
+    #[error("no reply")]
+    NoReply,
+
+    /// No peer stored or served the chunk
+    #[error("chunk {0} is unavailable")]
+    Unavailable(Id),
+
+    /// The message has no attachment with this name
+    #[error("unknown attachment {0}")]
+    UnknownAttachment(String),
+}
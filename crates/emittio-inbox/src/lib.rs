@@ -1,55 +1,212 @@
-use std::collections::HashMap;
-use actorify::{actor, tokio::io::AsyncRead};
-use emittio_crypto::{id::Id, kem::{Kem, PublicKey}, tag::{TagAddress, TagVerifier}};
-use emittio_network::{actor::NetworkActorHandle, query::Query};
+pub mod chunks;
+pub mod error;
+
+use std::{collections::HashMap, pin::pin, time::Duration};
+
+use actorify::{actor, tokio::{io::{AsyncRead, AsyncReadExt}, time::timeout}};
+use bytes::Bytes;
+use emittio_crypto::{OsRng, RngCore, blake3, ciphertext::Sealed, derivable::Derivable, error::CryptoError, id::{Id, Mask}, kem::{Capsule, Kem, PublicKey, SharedSecret}, tag::{TagAddress, TagVerifier}};
+use emittio_dht::query::{DhtGet, DhtPut};
+use emittio_network::{actor::NetworkActorHandle, error::NetworkError, query::Queryable, registry::assert_unique};
+use emittio_pointer::{error::BlockError, query::{CountPointers, GetMask, GetPointers, PutPointer}, types::{BlockTime, Pointer}, utils::{block_time, current_time}};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 
+use crate::{chunks::{put_chunks, read_chunks}, error::InboxError};
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Message {
     pub from: Address,
 	pub to: Id,
 	pub subject: String,
+	/// Key of the body and attachment chunks
+	pub key: SharedSecret,
 	pub text_root: Vec<Id>,
-	pub attachments: HashMap<String, u64>, // Names and locations of attachments
+	pub attachments: Vec<Attachment>,
+	/// Chunks of all attachments stored back to back
 	pub attachment_root: Vec<Id>,
 }
 
-pub type Bucket = ();
+/// File attached to a message
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+    /// Where the attachment starts in the attachment chunks
+    pub offset: u64,
+}
+
+/// File to attach to a sent message. `reader` must yield exactly `size` bytes
+pub struct AttachmentSource {
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+    pub reader: Box<dyn AsyncRead + Send + Unpin>,
+}
+
+pub type Bucket = Id;
+
+/// How many hex digits of the bucket an inbox uses at most. Nodes split blocks into finer buckets as they fill up,
+/// a lower precision keeps the inbox in a coarser bucket shared with more inboxes. That hides the recipient among more
+/// of them, but every pull scans more pointers. Senders learn it from the `Address`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BucketPrecision(pub u8);
+
+impl Default for BucketPrecision {
+    /// Follows the nodes' masks
+    fn default() -> Self {
+        Self(32)
+    }
+}
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Address {
     message_pk: PublicKey,
     tag_address: TagAddress,
+    precision: BucketPrecision,
+}
+
+/// How a message is stored in the DHT. It's sealed with a one-time key, so envelopes can't be linked to their sender
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    pk: PublicKey,
+    capsule: Vec<u8>,
+    message: Sealed<Message>,
+}
+
+impl Envelope {
+    fn seal(message: &Message, to: &PublicKey) -> Result<Self, InboxError> {
+        let one_time = Kem::random();
+        let (capsule, shared) = one_time.sk.shared(to)?;
+
+        // Every shared secret seals a single message, so the nonce can be fixed
+        let message = Sealed::encrypt(&shared, message, [0; 12], &[])?;
+
+        Ok(Self { pk: one_time.pk, capsule: capsule.to_vec(), message })
+    }
+
+    fn open(self, kem: &Kem) -> Result<Message, InboxError> {
+        let capsule: Capsule = self.capsule.try_into().map_err(|_| CryptoError::InvalidSharedKey)?;
+        let shared = kem.sk.shared_from_capsule(&self.pk, &capsule)?;
+
+        Ok(self.message.decrypt(shared, &[])?)
+    }
 }
 
-pub type TimeBlock = u64;
+// Queries of different crates must not share a `(SERVICE_ID, METHOD_ID)` pair
+const _: () = assert_unique(&[emittio_network::lookup::QUERIES, emittio_pointer::QUERIES, emittio_dht::QUERIES]);
+
+pub type TimeBlock = BlockTime;
 const CHAN_SIZE: usize = 1024;
-const TIME_BLOCK_SIZE: TimeBlock = 30 * 60;
+/// How far back the first pull scans. Older blocks have expired on most nodes
+const SCAN_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long a node may stay silent while it streams the pointers of a bucket, the next node is asked after that
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages found by a pull. The inbox only records them once the whole pull succeeds
+#[derive(Default)]
+struct Pulled {
+    messages: Vec<(Id, Message)>,
+    received: HashMap<Id, TimeBlock>,
+    unavailable: HashMap<Id, TimeBlock>,
+}
+
+impl Pulled {
+    fn contains(&self, cid: &Id) -> bool {
+        self.received.contains_key(cid) || self.unavailable.contains_key(cid)
+    }
+}
 
 pub struct InboxActor {
     message_sk: Kem,
     tag_verifier: TagVerifier,
     network: NetworkActorHandle,
     last_refresh_time: u64,
+    precision: BucketPrecision,
+    /// Messages already returned from blocks that are scanned again, by cid
+    received: HashMap<Id, TimeBlock>,
+    /// Messages whose content couldn't be fetched, by cid. They are retried on every pull until their block leaves the scan window
+    unavailable: HashMap<Id, TimeBlock>,
 }
 
 #[actor]
 impl InboxActor {
     #[command]
-    async fn send(&mut self, subject: String, to: Address, body: Box<dyn AsyncRead + Send + Unpin>, #[callback] cb: ()) {
-        todo!()
+    /// Uploads the message and publishes a pointer to it for the recipient, returning the message's cid
+    async fn send(&mut self, subject: String, to: Address, body: Box<dyn AsyncRead + Send + Unpin>, attachments: Vec<AttachmentSource>, #[callback] cb: Result<Id, InboxError>) {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+
+        let result = async {
+            let text_root = self.put_text(&key, body).await?;
+            let (attachments, attachment_root) = self.put_attachments(&key, attachments).await?;
+
+            let message = Message { from: self.address(), to: to.message_pk.id(), subject, key, text_root, attachments, attachment_root };
+
+            self.send_message(&message, &to).await
+        };
+
+        cb.send(result.await).ok();
     }
 
+    /// Scans the blocks since the last successful pull for pointers tagged for this inbox and returns the new messages.
+    /// The current block is scanned again on the next pull, as it may still get pointers.
+    /// Messages whose content can't be fetched don't fail the pull, they are retried on the next ones
     #[command]
-    async fn pull(&mut self, #[callback] cb: Vec<(Id, Message)>) {
-        let block = self.time_block();
-        let bucket = self.bucket();
+    async fn pull(&mut self, #[callback] cb: Result<Vec<(Id, Message)>, InboxError>) {
+        let now = current_time();
+        let oldest = block_time(now.saturating_sub(SCAN_WINDOW.as_secs()));
+
+        self.unavailable.retain(|_, block| *block >= oldest);
+
+        let result = async {
+            let mut pulled = Pulled::default();
+
+            for (&cid, &block) in &self.unavailable {
+                self.recv_new(cid, block, &mut pulled).await;
+            }
+
+            for block in self.time_block().max(oldest)..=block_time(now) {
+                for cid in self.scan_block(block).await? {
+                    // Unavailable ones were just retried
+                    if !self.received.contains_key(&cid) && !self.unavailable.contains_key(&cid) && !pulled.contains(&cid) {
+                        self.recv_new(cid, block, &mut pulled).await;
+                    }
+                }
+            }
+
+            Ok(pulled)
+        };
+
+        // A failed pull leaves the inbox as it was, so its messages are found again by the next one
+        let result = result.await.map(|pulled| {
+            self.last_refresh_time = now;
+            self.received.extend(pulled.received);
+            self.received.retain(|_, block| *block >= block_time(now));
+            self.unavailable = pulled.unavailable;
+
+            pulled.messages
+        });
+
+        cb.send(result).ok();
+    }
 
-        // let pointers = Vec::new();
+    /// Reads the body of the message, fetching its chunks as they are read
+    #[command]
+    async fn get_text(&mut self, message: Message, #[callback] cb: Box<dyn AsyncRead + Send + Unpin>) {
+        cb.send(self.read_text(&message.key, message.text_root)).ok();
+    }
 
-        // self.network.query_stream(Query::)
+    /// Reads a single attachment of the message, fetching only the chunks it spans
+    #[command]
+    async fn get_attachment(&mut self, message: Message, name: String, #[callback] cb: Result<Box<dyn AsyncRead + Send + Unpin>, InboxError>) {
+        let reader = match message.attachments.iter().find(|attachment| attachment.name == name) {
+            Some(attachment) => Ok(self.read_attachment(&message.key, message.attachment_root.clone(), attachment)),
+            None => Err(InboxError::UnknownAttachment(name)),
+        };
 
-        todo!()
+        cb.send(reader).ok();
     }
 
     pub fn new(network: NetworkActorHandle, message_sk: Kem, tag_verifier: TagVerifier) -> Self {
@@ -58,35 +215,408 @@ impl InboxActor {
             network,
             tag_verifier,
             last_refresh_time: 0,
+            precision: BucketPrecision::default(),
+            received: HashMap::new(),
+            unavailable: HashMap::new(),
         }
     }
 
+    /// Changes the bucket precision. The inbox gets a new `Address`, messages sent to the old one are no longer found
+    pub fn with_precision(mut self, precision: BucketPrecision) -> Self {
+        self.precision = precision;
+        self
+    }
+
     fn time_block(&self) -> TimeBlock {
-        self.last_refresh_time / TIME_BLOCK_SIZE
+        block_time(self.last_refresh_time)
+    }
+
+    /// Cids of the pointers of the block tagged for this inbox
+    async fn scan_block(&self, time: BlockTime) -> Result<Vec<Id>, InboxError> {
+        match (CountPointers { time }).query(&self.network).await? {
+            Some(Ok(0)) | Some(Err(BlockError::Expired)) => return Ok(Vec::new()),
+            Some(Ok(_)) => {},
+            None => return Err(InboxError::NoReply),
+        }
+
+        let mask = match (GetMask { time }).query(&self.network).await? {
+            Some(Ok(mask)) => mask,
+            Some(Err(BlockError::Expired)) => return Ok(Vec::new()),
+            None => return Err(InboxError::NoReply),
+        };
+
+        let previous_mask = match (GetMask { time: time.saturating_sub(1) }).query(&self.network).await? {
+            Some(Ok(mask)) => Some(mask),
+            Some(Err(BlockError::Expired)) => None,
+            None => return Err(InboxError::NoReply),
+        };
+
+        let mut cids = Vec::new();
+
+        for bucket in block_buckets(&self.address(), time, &mask, previous_mask.as_ref()) {
+            cids.extend(self.scan_bucket(time, bucket).await?);
+        }
+
+        Ok(cids)
+    }
+
+    /// Cids of the pointers of the bucket tagged for this inbox
+    async fn scan_bucket(&self, time: BlockTime, bucket: Bucket) -> Result<Vec<Id>, InboxError> {
+        let query = GetPointers { time, bucket, cursor: 0, count: u64::MAX };
+
+        // Any node of the bucket has all of its pointers, the next one is only asked if a stream breaks or stalls
+        for peer in self.network.select(query.peer_selection()).await.map_err(NetworkError::from)? {
+            let Ok(pages) = query.query_stream(&self.network, peer).await else {
+                continue;
+            };
+            let mut pages = pin!(pages);
+            let mut cids = Vec::new();
+
+            loop {
+                match timeout(SCAN_TIMEOUT, pages.next()).await {
+                    Ok(Some(Ok(Ok(pointers)))) => cids.extend(pointers.into_iter()
+                        .filter(|pointer| self.tag_verifier.verify(pointer.tag().clone()))
+                        .map(|pointer| *pointer.cid())),
+                    Ok(Some(Ok(Err(BlockError::Expired)))) => return Ok(Vec::new()),
+                    Ok(Some(Err(_))) | Err(_) => break,
+                    Ok(None) => return Ok(cids),
+                }
+            }
+        }
+
+        Err(InboxError::NoReply)
     }
 
-    fn bucket(&self) -> Bucket {
-        todo!()
+    /// Receives a message found in the block, adding it to `pulled`. Content that can't be fetched is recorded as unavailable
+    async fn recv_new(&self, cid: Id, block: TimeBlock, pulled: &mut Pulled) {
+        match self.recv_message(cid).await {
+            Ok(message) => {
+                pulled.messages.extend(message.map(|message| (cid, message)));
+                pulled.received.insert(cid, block);
+            }
+            Err(_) => { pulled.unavailable.insert(cid, block); },
+        }
     }
-        
-    async fn recv_message(&self) -> Message {
-        todo!("Checks for new messages")
+
+    /// Fetches and opens the message. Content that isn't a message for this inbox is skipped
+    async fn recv_message(&self, cid: Id) -> Result<Option<Message>, InboxError> {
+        let Some(Ok(bytes)) = (DhtGet { cid }).query(&self.network).await? else {
+            return Err(InboxError::Unavailable(cid));
+        };
+
+        Ok(postcard::from_bytes::<Envelope>(&bytes).ok().and_then(|envelope| envelope.open(&self.message_sk).ok()))
+    }
+    /// Seals the message for the recipient, puts it to the DHT and publishes a pointer with a tag only the recipient recognizes
+    async fn send_message(&self, message: &Message, to: &Address) -> Result<Id, InboxError> {
+        let bytes = Bytes::from(postcard::to_stdvec(&Envelope::seal(message, &to.message_pk)?)?);
+        let cid = Id::hash_bytes(&bytes);
+
+        match (DhtPut { bytes }).query(&self.network).await? {
+            Some(Ok(())) => {},
+            _ => return Err(InboxError::Unavailable(cid)),
+        }
+
+        // Nodes place pointers into their current block
+        let time = block_time(current_time());
+        let Some(Ok(mask)) = (GetMask { time }).query(&self.network).await? else {
+            return Err(InboxError::NoReply);
+        };
+
+        let pointer = Pointer::new(to.tag_address.generate_tag(), cid);
+
+        match (PutPointer { bucket: pointer_bucket(to, time, &mask), pointer }).query(&self.network).await? {
+            Some(Ok(())) => Ok(cid),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(InboxError::NoReply),
+        }
     }
-    async fn send_message(&self, _message: Message) -> Id {
-        todo!()
+
+    /// Address other inboxes send to
+    fn address(&self) -> Address {
+        Address { message_pk: self.message_sk.pk.clone(), tag_address: self.tag_verifier.address(), precision: self.precision }
     }
     
-    async fn get_text(&self, _chunks: Vec<Id>) -> Box<dyn AsyncRead + Send + Unpin> {
-        todo!()
+    fn read_text(&self, key: &SharedSecret, chunks: Vec<Id>) -> Box<dyn AsyncRead + Send + Unpin> {
+        Box::new(read_chunks(self.network.clone(), text_key(key), chunks, 0))
+    }
+    /// Uploads the body of a message in chunks encrypted under `key`, returning the message's `text_root`
+    async fn put_text(&self, key: &SharedSecret, stream: Box<dyn AsyncRead + Send + Unpin>) -> Result<Vec<Id>, InboxError> {
+        Ok(put_chunks(&self.network, &text_key(key), stream).await?.0)
+    }
+
+    fn read_attachment(&self, key: &SharedSecret, chunks: Vec<Id>, attachment: &Attachment) -> Box<dyn AsyncRead + Send + Unpin> {
+        Box::new(read_chunks(self.network.clone(), attachments_key(key), chunks, attachment.offset).take(attachment.size))
+    }
+    /// Uploads the attachments back to back in chunks encrypted under `key`, returning their metadata and the `attachment_root`
+    async fn put_attachments(&self, key: &SharedSecret, sources: Vec<AttachmentSource>) -> Result<(Vec<Attachment>, Vec<Id>), InboxError> {
+        let mut attachments = Vec::new();
+        let mut offset = 0;
+        let mut reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(actorify::tokio::io::empty());
+
+        for source in sources {
+            attachments.push(Attachment { name: source.name, mime: source.mime, size: source.size, offset });
+            offset += source.size;
+            reader = Box::new(reader.chain(source.reader.take(source.size)));
+        }
+
+        let (chunks, len) = put_chunks(&self.network, &attachments_key(key), reader).await?;
+
+        // A short reader would shift the offsets of the following attachments
+        if len != offset {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok((attachments, chunks))
+    }
+}
+
+/// Bucket of the recipient's pointers in the block. It changes every block, so buckets of one inbox can't be linked,
+/// and is coarsened by the block's `mask` and the recipient's precision
+fn pointer_bucket(address: &Address, time: BlockTime, mask: &Mask) -> Bucket {
+    let mut mask = mask.clone();
+
+    for digit in mask.0.iter_mut().skip(address.precision.0 as usize) {
+        *digit = 0;
+    }
+
+    Id::hash_from(&(&address.tag_address, time)).expect("address is always encodable").bucket(&mask)
+}
+
+/// Buckets of the block that may hold the recipient's pointers. Pointers sent at the end of the previous block may have landed in this one:
+/// their bucket was computed with the previous block and its mask, then normalized by the node with the mask of this block
+fn block_buckets(address: &Address, time: BlockTime, mask: &Mask, previous_mask: Option<&Mask>) -> Vec<Bucket> {
+    let mut buckets = vec![pointer_bucket(address, time, mask)];
+
+    if let Some(previous_mask) = previous_mask {
+        let previous = pointer_bucket(address, time.saturating_sub(1), previous_mask).bucket(mask);
+
+        if !buckets.contains(&previous) {
+            buckets.push(previous);
+        }
+    }
+
+    buckets
+}
+
+/// Key of the body chunks, derived from the message key
+fn text_key(key: &SharedSecret) -> SharedSecret {
+    blake3::keyed_hash(key, b"text").into()
+}
+
+/// Key of the attachment chunks, derived from the message key
+fn attachments_key(key: &SharedSecret) -> SharedSecret {
+    blake3::keyed_hash(key, b"attachments").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use actorify::{Actor, tokio_util::sync::CancellationToken};
+    use emittio_dht::{DHT_SERVICE_ID, service::DhtStorage};
+    use emittio_network::{error::ServiceError, service::{IncomingQuery, Service}, sim::SimNetwork};
+    use emittio_pointer::{POINTER_SERVICE_ID, query::GetPointers, service::{DEFAULT_RETENTION, PointerStorage}};
+
+    use super::*;
+
+    fn source(name: &str, bytes: &'static [u8], size: u64) -> AttachmentSource {
+        AttachmentSource { name: name.into(), mime: "text/plain".into(), size, reader: Box::new(bytes) }
+    }
+
+    #[tokio::test]
+    async fn test_attachments() {
+        let sim = SimNetwork::new(0);
+        let dir = tempfile::tempdir().unwrap();
+        let (node, peer) = sim.spawn_node("node");
+        node.register(DHT_SERVICE_ID, DhtStorage::new(dir.path().into())).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        let inbox = InboxActor::new(client, Kem::random(), TagVerifier::random());
+        let key = [3; 32];
+
+        let (attachments, chunks) = inbox.put_attachments(&key, vec![source("a.txt", b"first", 5), source("b.txt", b"second", 6)]).await.expect("put failed");
+        assert_eq!(attachments[1], Attachment { name: "b.txt".into(), mime: "text/plain".into(), size: 6, offset: 5 });
+
+        let mut read = Vec::new();
+        inbox.read_attachment(&key, chunks, &attachments[1]).read_to_end(&mut read).await.expect("read failed");
+        assert_eq!(read, b"second");
+
+        // A reader shorter than its declared size
+        assert!(inbox.put_attachments(&key, vec![source("a.txt", b"first", 6), source("b.txt", b"second", 6)]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_and_pull() {
+        let sim = SimNetwork::new(0);
+        let dht_dir = tempfile::tempdir().unwrap();
+        let pointer_dir = tempfile::tempdir().unwrap();
+
+        let (node, peer) = sim.spawn_node("node");
+        node.register(DHT_SERVICE_ID, DhtStorage::new(dht_dir.path().into())).await.expect("register failed");
+        node.register(POINTER_SERVICE_ID, PointerStorage::open(pointer_dir.path().into(), DEFAULT_RETENTION).await.expect("open failed")).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        let recipient = InboxActor::new(client.clone(), Kem::derive([1; 32]), TagVerifier::derive([2; 32]));
+        let address = recipient.address();
+        let (recipient, actor) = recipient.run(CancellationToken::new());
+        tokio::spawn(actor);
+
+        let (sender, actor) = InboxActor::new(client.clone(), Kem::random(), TagVerifier::random()).run(CancellationToken::new());
+        tokio::spawn(actor);
+
+        let cid = sender.send("subject".into(), address.clone(), Box::new(&b"body"[..]), Vec::new()).await
+            .expect("channel closed")
+            .expect("send failed");
+
+        // The node's first block has an empty mask
+        let time = block_time(current_time());
+        let query = GetPointers { time, bucket: pointer_bucket(&address, time, &Mask([0; 32])), cursor: 0, count: 10 };
+        let pointers = query.query(&client).await.expect("query failed").expect("no reply").expect("block expired");
+
+        assert_eq!(pointers.len(), 1);
+        assert_eq!(pointers[0].cid(), &cid);
+        assert!(TagVerifier::derive([2; 32]).verify(pointers[0].tag().clone()));
+
+        let messages = recipient.pull().await.expect("channel closed").expect("pull failed");
+        assert_eq!(messages.len(), 1);
+
+        let (id, message) = &messages[0];
+        assert_eq!((id, message.subject.as_str()), (&cid, "subject"));
+
+        let mut body = Vec::new();
+        recipient.get_text(message.clone()).await.expect("channel closed").read_to_end(&mut body).await.expect("read failed");
+        assert_eq!(body, b"body");
+
+        // The current block is scanned again, but its messages are returned once
+        assert!(recipient.pull().await.expect("channel closed").expect("pull failed").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pull_unavailable() {
+        let sim = SimNetwork::new(0);
+        let dht_dir = tempfile::tempdir().unwrap();
+        let pointer_dir = tempfile::tempdir().unwrap();
+
+        let (node, peer) = sim.spawn_node("node");
+        node.register(DHT_SERVICE_ID, DhtStorage::new(dht_dir.path().into())).await.expect("register failed");
+        node.register(POINTER_SERVICE_ID, PointerStorage::open(pointer_dir.path().into(), DEFAULT_RETENTION).await.expect("open failed")).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        let recipient = InboxActor::new(client.clone(), Kem::random(), TagVerifier::random());
+        let address = recipient.address();
+        let (recipient, actor) = recipient.run(CancellationToken::new());
+        tokio::spawn(actor);
+
+        let (sender, actor) = InboxActor::new(client.clone(), Kem::random(), TagVerifier::random()).run(CancellationToken::new());
+        tokio::spawn(actor);
+
+        let lost = sender.send("lost".into(), address.clone(), Box::new(&b"body"[..]), Vec::new()).await.expect("channel closed").expect("send failed");
+        let cid = sender.send("subject".into(), address, Box::new(&b"body"[..]), Vec::new()).await.expect("channel closed").expect("send failed");
+
+        // The content of the first message is gone for now
+        let path = emittio_dht::service::blob_path(dht_dir.path(), &lost);
+        let envelope = tokio::fs::read(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        let messages = recipient.pull().await.expect("channel closed").expect("pull failed");
+        assert_eq!(messages.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![cid]);
+
+        // It is retried once it's back
+        tokio::fs::write(&path, envelope).await.unwrap();
+
+        let messages = recipient.pull().await.expect("channel closed").expect("pull failed");
+        assert_eq!(messages.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![lost]);
+    }
+
+    /// Pointer service of a node that is down
+    struct Down;
+
+    impl Service for Down {
+        async fn handle(&mut self, _incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            Err(ServiceError::RateLimited)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_failed() {
+        let sim = SimNetwork::new(0);
+        let dht_dir = tempfile::tempdir().unwrap();
+        let pointer_dir = tempfile::tempdir().unwrap();
+
+        let (node, peer) = sim.spawn_node("node");
+        node.register(DHT_SERVICE_ID, DhtStorage::new(dht_dir.path().into())).await.expect("register failed");
+        node.register(POINTER_SERVICE_ID, PointerStorage::open(pointer_dir.path().into(), DEFAULT_RETENTION).await.expect("open failed")).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        let recipient = InboxActor::new(client.clone(), Kem::random(), TagVerifier::random());
+        let address = recipient.address();
+        let (recipient, actor) = recipient.run(CancellationToken::new());
+        tokio::spawn(actor);
+
+        let (sender, actor) = InboxActor::new(client.clone(), Kem::random(), TagVerifier::random()).run(CancellationToken::new());
+        tokio::spawn(actor);
+
+        let cid = sender.send("subject".into(), address, Box::new(&b"body"[..]), Vec::new()).await.expect("channel closed").expect("send failed");
+
+        let path = emittio_dht::service::blob_path(dht_dir.path(), &cid);
+        let envelope = tokio::fs::read(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert!(recipient.pull().await.expect("channel closed").expect("pull failed").is_empty());
+
+        // The retry gets the message, but the scan that follows fails
+        tokio::fs::write(&path, envelope).await.unwrap();
+        node.register(POINTER_SERVICE_ID, Down).await.expect("register failed");
+
+        assert!(recipient.pull().await.expect("channel closed").is_err());
+
+        // The pointers are gone by now, the message is still retried
+        let pointer_dir = tempfile::tempdir().unwrap();
+        node.register(POINTER_SERVICE_ID, PointerStorage::open(pointer_dir.path().into(), DEFAULT_RETENTION).await.expect("open failed")).await.expect("register failed");
+
+        let messages = recipient.pull().await.expect("channel closed").expect("pull failed");
+        assert_eq!(messages.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![cid]);
     }
-    async fn put_text(&self, _stream: Box<dyn AsyncRead + Send + Unpin>) -> Vec<Id> {
-        todo!()
+
+    #[test]
+    fn test_pointer_bucket() {
+        let mut address = Address { message_pk: Kem::random().pk, tag_address: TagVerifier::random().address(), precision: BucketPrecision::default() };
+        let mask = Mask::new_hex_mask(1, 0x10000);
+
+        // Four digits of the mask are used
+        let bucket = pointer_bucket(&address, 1, &mask);
+        assert!(bucket.0[..4].iter().any(|digit| *digit != 0) && bucket.0[4..].iter().all(|digit| *digit == 0));
+        assert_ne!(bucket, pointer_bucket(&address, 2, &mask), "buckets change every block");
+
+        address.precision = BucketPrecision(2);
+        assert_eq!(pointer_bucket(&address, 1, &mask).0[..2], bucket.0[..2]);
+        assert!(pointer_bucket(&address, 1, &mask).0[2..].iter().all(|digit| *digit == 0));
+
+        address.precision = BucketPrecision(0);
+        assert_eq!(pointer_bucket(&address, 1, &mask), Id::default(), "all inboxes share a single bucket");
     }
 
-    // async fn get_attachment(&self, _chunks: Vec<Id>, _location: u64) -> Reader {
-    //     todo!()
-    // }
-    // async fn put_attachments(&self, _attachments: HashMap<String, u64>, _stream: Reader) -> Vec<Id> {
-    //     todo!()
-    // }
-}
\ No newline at end of file
+    #[test]
+    fn test_block_buckets() {
+        let address = Address { message_pk: Kem::derive([1; 32]).pk, tag_address: TagVerifier::derive([2; 32]).address(), precision: BucketPrecision::default() };
+
+        // The previous block had fewer pointers, so its mask was coarser
+        let previous_mask = Mask::new_hex_mask(1, 0x100);
+        let mask = Mask::new_hex_mask(1, 0x10000);
+
+        // A pointer sent at the end of the previous block and placed by the node into this one
+        let sent = pointer_bucket(&address, 1, &previous_mask).bucket(&mask);
+
+        let buckets = block_buckets(&address, 2, &mask, Some(&previous_mask));
+        assert_eq!(buckets, vec![pointer_bucket(&address, 2, &mask), sent]);
+        assert_ne!(sent, pointer_bucket(&address, 1, &mask), "the previous bucket depends on the previous mask");
+
+        assert_eq!(block_buckets(&address, 2, &mask, None), vec![pointer_bucket(&address, 2, &mask)], "expired previous block");
+    }
+}
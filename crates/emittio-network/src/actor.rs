@@ -1,25 +1,60 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet}, pin::Pin, time::Duration};
+use emittio_crypto::{derivable::Derivable, id::Id, kem::{Kem, SecretKey, SharedSecret}};
+use rand::{RngCore, rngs::OsRng, seq::IteratorRandom};
 use bytes::Bytes;
-use emittio_crypto::{derivable::Derivable, kem::{Kem, SecretKey, SharedSecret}};
+use tokio::{sync::mpsc, task::{JoinError, JoinSet}, time::Instant};
+use tokio_stream::{Stream, StreamExt, StreamMap};
 use tokio_util::task::JoinMap;
-use actorify::{Callback, Channel, actor, ok_or_reply};
-use rand::{RngCore, rngs::OsRng};
+use actorify::{ActorJoinMap, Callback, Channel, actor};
 
-use crate::{error::NetworkError, peer::{Peer, PeerId}, query::{PeerSelection, Query}, reply::Reply, session::Session, types::{Handshake, Packet, PayloadId}};
+use crate::{error::{NetworkError, ReplyError}, service::{ChunkStream, IncomingQuery, ServiceRequest}, stream::{MAX_STREAM_WINDOW, STREAM_IDLE_TIMEOUT}, peer::{Peer, PeerId}, pow::SeenStamps, query::{PeerSelection, Query, QueryId}, reply::{Reply, ReplyStatus}, lookup::{FindNode, NETWORK_SERVICE_ID}, query::Queryable, reputation::{PeerScore, Reputation, SavedScores}, routing::{K, RoutingTable}, session::{Session, handshake_auth, one_rtt_key}, transport::{CHAN_SIZE, Connection, Transport}, types::{Chunk, Frame, Handshake, Packet}};
 
 use crate::types::FrameData;
 
 type ConnId = u64;
 
-pub struct NetworkActor {
+/// How long the ephemeral key of an answered handshake is remembered after its peer disconnects
+const SPENT_HANDSHAKE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Streams we send are keyed by the requester and its query id
+type StreamKey = (PeerId, QueryId);
+
+/// Chunks of a stream we send. It ends with `None`, so the end can be reported to the requester
+type ChunkSource = Pin<Box<dyn Stream<Item = Option<Bytes>> + Send>>;
+
+/// Stream we receive
+struct IncomingStream {
+    peer_id: PeerId,
+    chunks: mpsc::Sender<Result<Bytes, NetworkError>>,
+    /// When the responder last sent a chunk
+    last_active: Instant,
+}
+
+/// Query waiting for replies of the peers it was sent to
+struct PendingQuery {
+    waiting: HashSet<PeerId>,
+    replies: Vec<(PeerId, Reply)>,
+    callback: Callback<Result<Vec<(PeerId, Reply)>, NetworkError>>,
+}
+
+pub struct NetworkActor<T: Transport> {
+    transport: T,
+    /// Static key of a node. Without it the actor can only initiate sessions
+    identity: Option<Kem>,
+
     zero_rtt_resp_states: HashMap<PeerId, SharedSecret>,
-    one_rtt_init_states: HashMap<PeerId, SecretKey>,
+    /// Ephemeral key and 0-RTT secret of handshakes we initiated
+    one_rtt_init_states: HashMap<PeerId, (SecretKey, SharedSecret)>,
     one_rtt_resp_states: HashMap<PeerId, SharedSecret>,
+    /// Ephemeral keys of handshakes we answered, with the time they were answered. Initiators use a new key for every handshake
+    spent_handshakes: HashMap<PeerId, Instant>,
 
     zero_rtt_sessions: HashMap<PeerId, Session>,
     one_rtt_sessions: HashMap<PeerId, Session>,
 
-    callbacks: HashMap<(PeerId, PayloadId), Callback<Result<Bytes, NetworkError>>>,
+    queries: HashMap<QueryId, PendingQuery>,
+    query_deadlines: ActorJoinMap<QueryId>,
+    next_query_id: QueryId,
     ttl_sessions: HashMap<u64, PeerId>,
 
     conns_by_peer: HashMap<PeerId, HashSet<ConnId>>,
@@ -28,27 +63,243 @@ pub struct NetworkActor {
     active_conns: HashMap<ConnId, Channel<Packet>>,
     connections: JoinMap<ConnId, NetworkError>,
     ttl_connections: HashMap<u64, ConnId>,
+    next_conn_id: ConnId,
+
+    packets_tx: Channel<(ConnId, Packet)>,
+    packets_rx: mpsc::Receiver<(ConnId, Packet)>,
+
+    routing: RoutingTable,
+    reputation: Reputation,
+    /// Peers selected explicitly that are not in the routing table. Forgotten once disconnected
+    contacts: HashMap<PeerId, Peer>,
+
+    services: HashMap<u16, Channel<ServiceRequest>>,
+    /// Stamps of queries we accepted, to reject replays
+    seen_stamps: SeenStamps,
+    /// Queries of remote peers being processed by services
+    handling: JoinSet<(PeerId, Reply)>,
+
+    /// Streams we receive, by the id of the query that opened them
+    incoming_streams: HashMap<QueryId, IncomingStream>,
+    /// Credit left of every stream we send
+    stream_credits: HashMap<StreamKey, u32>,
+    /// Streams we send that have credit left
+    outgoing_streams: StreamMap<StreamKey, ChunkSource>,
+    /// Streams we send that wait for more credit, with the time they were parked
+    parked_streams: HashMap<StreamKey, (ChunkSource, Instant)>,
+    /// Streamed queries of remote peers being opened by services
+    opening_streams: JoinSet<(StreamKey, u32, Result<ChunkStream, ReplyStatus>)>,
 }
 
 #[actor]
-impl NetworkActor {
-    /// Sends query to peers selected by `peer_selection` returning their replies
+impl<T: Transport> NetworkActor<T> {
+    /// Sends query to peers selected by `peer_selection` returning their replies.
+    /// Replies are returned once every peer has answered or `timeout` has passed, whichever comes first
     #[command]
-    async fn query(&mut self, peer_selection: PeerSelection, mut query: Query, #[callback] callback: Result<Vec<(PeerId, Reply)>, NetworkError>) {
-        // TODO: change to a counter
-        let query_id = OsRng.next_u32() as u16;
-        query.query_id = query_id;
-        let peers = self.select_peers(peer_selection);
+    async fn query(&mut self, peer_selection: PeerSelection, query: Query, timeout: Duration, #[callback] callback: Result<Vec<(PeerId, Reply)>, NetworkError>) {
+        let queries = self.select_peers(peer_selection)
+            .into_iter()
+            .map(|peer_id| (peer_id, query.clone()))
+            .collect();
+
+        self.start_query(queries, timeout, callback).await;
+    }
+
+    /// Sends every peer its own copy of the query, e.g. with a stamp bound to that peer. Works like `query` otherwise
+    #[command]
+    async fn query_peers(&mut self, queries: Vec<(Peer, Query)>, timeout: Duration, #[callback] callback: Result<Vec<(PeerId, Reply)>, NetworkError>) {
+        let queries = queries.into_iter()
+            .filter_map(|(peer, query)| {
+                let peer_id = self.select_peers(PeerSelection::Peers(vec![peer])).pop()?;
+                Some((peer_id, query))
+            })
+            .collect();
+
+        self.start_query(queries, timeout, callback).await;
+    }
+
+    /// Peers `peer_selection` currently resolves to
+    #[command]
+    async fn select(&mut self, peer_selection: PeerSelection, #[callback] callback: Vec<Peer>) {
+        let peers = self.select_peers(peer_selection)
+            .iter()
+            .filter_map(|peer_id| self.get_peer(peer_id))
+            .collect();
+
+        callback.send(peers).ok();
+    }
+
+    /// All copies of a query share the same query id
+    async fn start_query(&mut self, queries: Vec<(PeerId, Query)>, timeout: Duration, callback: Callback<Result<Vec<(PeerId, Reply)>, NetworkError>>) {
+        let query_id = self.take_query_id();
+
+        let mut waiting = HashSet::new();
+        let mut last_error = None;
+
+        for (peer_id, mut query) in queries {
+            query.query_id = query_id;
 
-        ok_or_reply!(callback, {
-            for peer_id in peers {
-                self.send(&peer_id, &FrameData::Query(query.clone())).await?;
+            match self.send(&peer_id, &FrameData::Query(query)).await {
+                Ok(()) => { waiting.insert(peer_id); },
+                Err(err) => last_error = Some(err),
             }
+        }
+
+        if waiting.is_empty() {
+            callback.send(last_error.map_or(Ok(Vec::new()), Err)).ok();
+            return;
+        }
+
+        self.query_deadlines.spawn(query_id, tokio::time::sleep(timeout));
+        self.queries.insert(query_id, PendingQuery { waiting, replies: Vec::new(), callback });
+    }
+
+    #[listen(self.query_deadlines.join_next())]
+    async fn expire_query(&mut self, (query_id, result): (QueryId, Result<(), JoinError>)) {
+        // Deadlines of completed queries are aborted
+        if result.is_ok() {
+            self.finish_query(query_id);
+        }
+    }
+
+    fn take_query_id(&mut self) -> QueryId {
+        let query_id = self.next_query_id;
+        self.next_query_id = self.next_query_id.wrapping_add(1);
+        query_id
+    }
+
+    async fn recv_query(&mut self, peer_id: PeerId, query: Query) {
+        if query.service_id == NETWORK_SERVICE_ID && query.stream.is_none() {
+            let reply = self.handle_network_query(&query);
+            self.send(&peer_id, &FrameData::Reply(reply)).await.ok();
+            return;
+        }
+
+        let Some(service) = self.services.get(&query.service_id) else {
+            self.reject(&peer_id, &query, ReplyStatus::UnknownService).await;
+            return;
+        };
+
+        // Services spend the stamp once they have verified it
+        let incoming = IncomingQuery { query: query.clone(), local_id: *self.routing.local_id(), seen_stamps: self.seen_stamps.clone() };
+
+        if let Some(window) = query.stream {
+            // The window comes from the requester, so it is bounded before it becomes our credit
+            let window = window.clamp(1, MAX_STREAM_WINDOW);
+            let (callback, result) = Callback::new();
+
+            if service.send(ServiceRequest::Stream { incoming, callback }).await.is_err() {
+                self.services.remove(&query.service_id);
+                self.reject(&peer_id, &query, ReplyStatus::UnknownService).await;
+                return;
+            }
+
+            self.opening_streams.spawn(async move {
+                let chunks = match result.await {
+                    Ok(Ok(chunks)) => Ok(chunks),
+                    Ok(Err(err)) => Err(ReplyStatus::from(&err)),
+                    Err(_) => Err(ReplyStatus::HandlerError),
+                };
+
+                ((peer_id, query.query_id), window, chunks)
+            });
+            return;
+        }
+
+        let (callback, result) = Callback::new();
+
+        if service.send(ServiceRequest::Query { incoming, callback }).await.is_err() {
+            // The service has stopped
+            self.services.remove(&query.service_id);
+            self.reject(&peer_id, &query, ReplyStatus::UnknownService).await;
+            return;
+        }
+
+        self.handling.spawn(async move {
+            let reply = match result.await {
+                Ok(Ok(bytes)) => Reply::ok(&query, bytes),
+                Ok(Err(err)) => Reply::error(&query, ReplyStatus::from(&err)),
+                Err(_) => Reply::error(&query, ReplyStatus::HandlerError),
+            };
 
-            Ok(())
+            (peer_id, reply)
         });
-        todo!("Store callback into a reply accumulator");
-        // self.callbacks.insert((*peer_id, payload_id), callback);
+    }
+
+    /// Answers a query that won't be handled with an error `status`. Streamed queries are answered with an ended stream
+    async fn reject(&mut self, peer_id: &PeerId, query: &Query, status: ReplyStatus) {
+        let data = match query.stream {
+            Some(_) => FrameData::Chunk(Chunk::End { query_id: query.query_id, status }),
+            None => FrameData::Reply(Reply::error(query, status)),
+        };
+
+        self.send(peer_id, &data).await.ok();
+    }
+
+    /// Answers queries of the service implemented by the actor itself
+    fn handle_network_query(&self, query: &Query) -> Reply {
+        let result = match query.method_id {
+            FindNode::METHOD_ID => postcard::from_bytes::<FindNode>(&query.bytes)
+                .and_then(|find_node| postcard::to_stdvec(&self.closest(&find_node.target, K))),
+            _ => return Reply::error(query, ReplyStatus::HandlerError),
+        };
+
+        match result {
+            Ok(bytes) => Reply::ok(query, bytes.into()),
+            Err(_) => Reply::error(query, ReplyStatus::HandlerError),
+        }
+    }
+
+    fn closest(&self, target: &Id, count: usize) -> Vec<Peer> {
+        self.routing.closest(target, count)
+            .iter()
+            .filter_map(|peer_id| self.routing.get(peer_id).cloned())
+            .collect()
+    }
+
+    fn recv_reply(&mut self, peer_id: &PeerId, reply: Reply) {
+        let query_id = reply.query_id;
+
+        let Some(query) = self.queries.get_mut(&query_id) else {
+            return;
+        };
+
+        // Ignores replies from peers that weren't asked or have already answered
+        if !query.waiting.remove(peer_id) {
+            return;
+        }
+
+        query.replies.push((*peer_id, reply));
+
+        if query.waiting.is_empty() {
+            self.finish_query(query_id);
+        }
+    }
+
+    /// Stops waiting for replies of a disconnected peer
+    fn forget_waiting(&mut self, peer_id: &PeerId) {
+        let finished: Vec<QueryId> = self.queries.iter_mut()
+            .filter_map(|(query_id, query)| (query.waiting.remove(peer_id) && query.waiting.is_empty()).then_some(*query_id))
+            .collect();
+
+        for query_id in finished {
+            self.finish_query(query_id);
+        }
+    }
+
+    fn finish_query(&mut self, query_id: QueryId) {
+        let Some(query) = self.queries.remove(&query_id) else {
+            return;
+        };
+
+        self.query_deadlines.abort(&query_id);
+
+        // Peers that didn't answer in time are probably offline
+        for peer_id in query.waiting.iter() {
+            self.routing.failed(peer_id);
+        }
+
+        query.callback.send(Ok(query.replies)).ok();
     }
 
     async fn send(&mut self, peer_id: &PeerId, data: &FrameData) -> Result<(), NetworkError> {
@@ -62,21 +313,348 @@ impl NetworkActor {
         Ok(())
     }
 
+    /// Takes over an inbound connection. It stays pending until the remote side identifies itself with a handshake
+    #[command]
+    async fn accept(&mut self, conn: Connection, #[callback] callback: ()) {
+        self.add_connection(conn, None);
+        callback.send(()).ok();
+    }
+
+    /// Remembers a peer so it can be dialed by its id. Peers that don't fit into the routing table are kept as replacements
+    #[command]
+    async fn add_peer(&mut self, peer: Peer, #[callback] callback: ()) {
+        self.routing.insert(peer);
+        callback.send(()).ok();
+    }
+
+    #[listen(self.packets_rx.recv())]
+    async fn recv_packet(&mut self, (conn_id, packet): (ConnId, Packet)) {
+        match packet {
+            Packet::Handshake(handshake) => {
+                self.recv_handshake(conn_id, handshake).await.ok();
+            }
+            Packet::Frame(frame) => {
+                let Some(peer_id) = self.peer_by_conn.get(&conn_id).copied() else {
+                    return; // Frames from unidentified connections are dropped
+                };
+
+                // Frames that fail to decrypt or are replayed are dropped
+                let Ok(data) = self.open_frame(&peer_id, &frame) else {
+                    return;
+                };
+
+                self.routing.seen(&peer_id);
+
+                match data {
+                    FrameData::Query(query) => self.recv_query(peer_id, query).await,
+                    FrameData::Reply(reply) => self.recv_reply(&peer_id, reply),
+                    FrameData::Chunk(chunk) => self.recv_chunk(peer_id, chunk).await,
+                }
+            }
+        }
+    }
+
+    #[command]
+    async fn local_id(&mut self, #[callback] callback: PeerId) {
+        callback.send(*self.routing.local_id()).ok();
+    }
+
+    /// Up to `count` peers of the routing table closest to `target`
+    #[command]
+    async fn closest_peers(&mut self, target: Id, count: u16, #[callback] callback: Vec<Peer>) {
+        callback.send(self.closest(&target, count as usize)).ok();
+    }
+
+    /// Routes incoming queries with `service_id` to `service`
+    #[command]
+    async fn register_service(&mut self, service_id: u16, service: Channel<ServiceRequest>, #[callback] callback: ()) {
+        self.services.insert(service_id, service);
+        callback.send(()).ok();
+    }
+
+    #[listen(self.handling.join_next())]
+    async fn send_reply(&mut self, result: Result<(PeerId, Reply), JoinError>) {
+        let Ok((peer_id, reply)) = result else {
+            return;
+        };
+
+        self.send(&peer_id, &FrameData::Reply(reply)).await.ok();
+    }
+
+    /// Opens a streamed query to `peer`, allowing it to send up to `window` chunks ahead of the ones we have consumed.
+    /// Chunks arrive through the returned receiver, which is closed when the stream ends
+    #[command]
+    async fn open_stream(&mut self, peer: Peer, mut query: Query, window: u32, #[callback] callback: Result<(QueryId, mpsc::Receiver<Result<Bytes, NetworkError>>), NetworkError>) {
+        let peer_id = peer.id;
+
+        if self.select_peers(PeerSelection::Peers(vec![peer])).is_empty() {
+            callback.send(Err(NetworkError::PeerNotFound(peer_id))).ok();
+            return;
+        }
+
+        let window = window.max(1);
+        let query_id = self.take_query_id();
+
+        query.query_id = query_id;
+        query.stream = Some(window);
+
+        if let Err(err) = self.send(&peer_id, &FrameData::Query(query)).await {
+            callback.send(Err(err)).ok();
+            return;
+        }
+
+        // One extra slot for the error that may end the stream
+        let (tx, rx) = mpsc::channel(window as usize + 1);
+
+        self.incoming_streams.insert(query_id, IncomingStream { peer_id, chunks: tx, last_active: Instant::now() });
+        callback.send(Ok((query_id, rx))).ok();
+    }
+
+    /// Allows the responder of a stream we receive to send `credit` more chunks
+    #[command]
+    async fn grant_credit(&mut self, query_id: QueryId, credit: u32, #[callback] callback: ()) {
+        if let Some(stream) = self.incoming_streams.get(&query_id) {
+            let peer_id = stream.peer_id;
+            self.send(&peer_id, &FrameData::Chunk(Chunk::Credit { query_id, credit })).await.ok();
+        }
+
+        callback.send(()).ok();
+    }
+
+    async fn recv_chunk(&mut self, peer_id: PeerId, chunk: Chunk) {
+        let query_id = chunk.query_id();
+
+        match chunk {
+            Chunk::Data { bytes, .. } => {
+                let Some(stream) = self.incoming_streams.get_mut(&query_id).filter(|s| s.peer_id == peer_id) else {
+                    return;
+                };
+
+                stream.last_active = Instant::now();
+
+                // The consumer is gone, or the channel is full because the responder ignored flow control
+                if stream.chunks.try_send(Ok(bytes)).is_err() {
+                    self.incoming_streams.remove(&query_id);
+                    self.send(&peer_id, &FrameData::Chunk(Chunk::Cancel { query_id })).await.ok();
+                }
+            }
+            Chunk::End { status, .. } => {
+                if !self.incoming_streams.get(&query_id).is_some_and(|s| s.peer_id == peer_id) {
+                    return;
+                }
+
+                let stream = self.incoming_streams.remove(&query_id).expect("stream exists");
+
+                if status != ReplyStatus::Ok {
+                    stream.chunks.try_send(Err(ReplyError::Remote(status).into())).ok();
+                }
+            }
+            Chunk::Credit { credit, .. } => {
+                let key = (peer_id, query_id);
+
+                let Some(credit_left) = self.stream_credits.get_mut(&key) else {
+                    return;
+                };
+
+                *credit_left = credit_left.saturating_add(credit);
+
+                if *credit_left > 0 && let Some((source, _)) = self.parked_streams.remove(&key) {
+                    self.outgoing_streams.insert(key, source);
+                }
+            }
+            Chunk::Cancel { .. } => self.stop_stream(&(peer_id, query_id)),
+        }
+    }
+
+    #[listen(self.opening_streams.join_next())]
+    async fn start_stream(&mut self, result: Result<(StreamKey, u32, Result<ChunkStream, ReplyStatus>), JoinError>) {
+        let Ok(((peer_id, query_id), window, chunks)) = result else {
+            return;
+        };
+
+        match chunks {
+            Ok(chunks) => {
+                let source: ChunkSource = Box::pin(chunks.map(Some).chain(tokio_stream::once(None)));
+
+                self.stream_credits.insert((peer_id, query_id), window);
+                self.outgoing_streams.insert((peer_id, query_id), source);
+            }
+            Err(status) => {
+                self.send(&peer_id, &FrameData::Chunk(Chunk::End { query_id, status })).await.ok();
+            }
+        }
+    }
+
+    /// Sends the next chunk of a stream with credit left, parking the stream once the credit runs out
+    #[listen(self.outgoing_streams.next())]
+    async fn send_chunk(&mut self, (key, chunk): (StreamKey, Option<Bytes>)) {
+        let (peer_id, query_id) = key;
+
+        let Some(bytes) = chunk else {
+            self.stop_stream(&key);
+            self.send(&peer_id, &FrameData::Chunk(Chunk::End { query_id, status: ReplyStatus::Ok })).await.ok();
+            return;
+        };
+
+        if self.send(&peer_id, &FrameData::Chunk(Chunk::Data { query_id, bytes })).await.is_err() {
+            self.stop_stream(&key);
+            return;
+        }
+
+        let Some(credit) = self.stream_credits.get_mut(&key) else {
+            return;
+        };
+
+        *credit = credit.saturating_sub(1);
+
+        if *credit == 0 && let Some(source) = self.outgoing_streams.remove(&key) {
+            self.parked_streams.insert(key, (source, Instant::now()));
+        }
+    }
+
+    fn stop_stream(&mut self, key: &StreamKey) {
+        self.stream_credits.remove(key);
+        self.outgoing_streams.remove(key);
+        self.parked_streams.remove(key);
+    }
+
+    /// Ends streams whose peer went silent: incoming ones that got no chunk and outgoing ones that got no credit for `STREAM_IDLE_TIMEOUT`
+    #[every(STREAM_IDLE_TIMEOUT / 4)]
+    async fn expire_streams(&mut self) {
+        let now = Instant::now();
+
+        let expired: Vec<(QueryId, PeerId)> = self.incoming_streams.iter()
+            .filter(|(_, stream)| now.duration_since(stream.last_active) >= STREAM_IDLE_TIMEOUT)
+            .map(|(query_id, stream)| (*query_id, stream.peer_id))
+            .collect();
+
+        for (query_id, peer_id) in expired {
+            let stream = self.incoming_streams.remove(&query_id).expect("stream exists");
+            stream.chunks.try_send(Err(NetworkError::StreamTimeout)).ok();
+
+            self.send(&peer_id, &FrameData::Chunk(Chunk::Cancel { query_id })).await.ok();
+        }
+
+        let parked: Vec<StreamKey> = self.parked_streams.iter()
+            .filter(|(_, (_, parked_at))| now.duration_since(*parked_at) >= STREAM_IDLE_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in parked {
+            self.stop_stream(&key);
+        }
+    }
+
+    /// Ends the streams of a disconnected peer
+    fn forget_streams(&mut self, peer_id: &PeerId) {
+        self.incoming_streams.retain(|_, stream| {
+            if stream.peer_id != *peer_id {
+                return true;
+            }
+
+            stream.chunks.try_send(Err(NetworkError::ConnectionClosed)).ok();
+            false
+        });
+
+        let keys: Vec<StreamKey> = self.stream_credits.keys().filter(|(id, _)| id == peer_id).copied().collect();
+
+        for key in keys {
+            self.stop_stream(&key);
+        }
+    }
+
+    #[listen(self.connections.join_next())]
+    async fn close_connection(&mut self, (conn_id, _): (ConnId, Result<NetworkError, JoinError>)) {
+        self.active_conns.remove(&conn_id);
+        self.pending_conns.remove(&conn_id);
+
+        if let Some(peer_id) = self.peer_by_conn.remove(&conn_id)
+            && let Some(conns) = self.conns_by_peer.get_mut(&peer_id)
+        {
+            conns.remove(&conn_id);
+
+            if conns.is_empty() {
+                self.conns_by_peer.remove(&peer_id);
+                // Sessions live as long as the peer stays connected, the next connection starts with a new handshake
+                self.forget_sessions(&peer_id);
+                self.forget_waiting(&peer_id);
+                self.forget_streams(&peer_id);
+                self.contacts.remove(&peer_id);
+            }
+        }
+    }
+
     /// Updates peer score based on reply verification results
     #[command]
     async fn verifications(&mut self, results: Vec<(PeerId, bool)>, #[callback] callback: ()) {
-        todo!("Update peer score");
+        for (peer_id, verified) in results {
+            self.reputation.record(peer_id, verified);
+        }
+
+        callback.send(()).ok();
+    }
+
+    /// Scores of all known peers, best first. Meant for debugging
+    #[command]
+    async fn scores(&mut self, #[callback] callback: Vec<(PeerId, PeerScore)>) {
+        callback.send(self.reputation.scores()).ok();
+    }
+
+    /// Scores of all known peers, to be restored with `restore_scores` after a restart
+    #[command]
+    async fn saved_scores(&mut self, #[callback] callback: SavedScores) {
+        callback.send(self.reputation.save()).ok();
     }
 
-    pub fn new() -> Self {
+    #[command]
+    async fn restore_scores(&mut self, saved: SavedScores, #[callback] callback: ()) {
+        self.reputation.restore(saved);
+        callback.send(()).ok();
+    }
+
+    #[every(Duration::from_secs(60))]
+    async fn prune_scores(&mut self) {
+        self.reputation.prune();
+    }
+
+    #[every(Duration::from_secs(60))]
+    async fn prune_stamps(&mut self) {
+        self.seen_stamps.prune();
+    }
+
+    /// Keys of connected initiators are kept, their handshakes could be replayed on another connection
+    #[every(Duration::from_secs(60))]
+    async fn prune_handshakes(&mut self) {
+        self.spent_handshakes.retain(|peer_id, answered_at| self.conns_by_peer.contains_key(peer_id) || answered_at.elapsed() < SPENT_HANDSHAKE_TTL);
+    }
+
+    pub fn new(transport: T, identity: Option<Kem>) -> Self {
+        let (packets_tx, packets_rx) = Channel::new(CHAN_SIZE);
+
+        // Clients have no static identity, so their routing table is centered on a random id
+        let local_id = match &identity {
+            Some(identity) => identity.pk.id(),
+            None => {
+                let mut id = [0u8; 32];
+                OsRng.fill_bytes(&mut id);
+                Id(id)
+            }
+        };
+
         Self {
+            transport,
+            identity,
+
             zero_rtt_resp_states: HashMap::new(),
             one_rtt_init_states: HashMap::new(),
             one_rtt_resp_states: HashMap::new(),
+            spent_handshakes: HashMap::new(),
 
             zero_rtt_sessions: HashMap::new(),
             one_rtt_sessions: HashMap::new(),
-            callbacks: HashMap::new(),
+            queries: HashMap::new(),
+            query_deadlines: ActorJoinMap::new(),
+            next_query_id: 0,
             ttl_sessions: HashMap::new(),
 
             conns_by_peer: HashMap::new(),
@@ -85,6 +663,24 @@ impl NetworkActor {
             active_conns: HashMap::new(),
             connections: JoinMap::new(),
             ttl_connections: HashMap::new(),
+            next_conn_id: 0,
+
+            packets_tx,
+            packets_rx,
+
+            routing: RoutingTable::new(local_id),
+            contacts: HashMap::new(),
+            reputation: Reputation::new(),
+
+            services: HashMap::new(),
+            seen_stamps: SeenStamps::default(),
+            handling: JoinSet::new(),
+
+            incoming_streams: HashMap::new(),
+            stream_credits: HashMap::new(),
+            outgoing_streams: StreamMap::new(),
+            parked_streams: HashMap::new(),
+            opening_streams: JoinSet::new(),
         }
     }
 
@@ -107,11 +703,12 @@ impl NetworkActor {
 
             let (capsule, shared) = keypair.sk.shared(&peer.pk)?;
 
-            self.one_rtt_init_states.insert(peer.id.clone(), keypair.sk);
+            self.one_rtt_init_states.insert(peer.id.clone(), (keypair.sk, shared));
 
             let handshake = Handshake {
                 pk: keypair.pk,
                 capsule,
+                auth: None,
             };
 
             let conn = self.select_connection(peer_id).await?;
@@ -126,14 +723,605 @@ impl NetworkActor {
         Ok(session)
     }
 
+    /// Completes our handshake if we initiated it, otherwise answers it as a responder
+    async fn recv_handshake(&mut self, conn_id: ConnId, handshake: Handshake) -> Result<(), NetworkError> {
+        // A key we answered before is a captured handshake replayed, it must not take over the initiator's sessions
+        if self.spent_handshakes.contains_key(&handshake.pk.id()) {
+            return Err(NetworkError::InvalidHandshake(handshake.pk.id()));
+        }
+
+        let peer_id = match self.peer_by_conn.get(&conn_id) {
+            Some(peer_id) => *peer_id,
+            None => {
+                // Initiators are identified by their ephemeral key
+                let peer_id = handshake.pk.id();
+                self.link_connection(conn_id, peer_id);
+                peer_id
+            }
+        };
+
+        if let Some((sk, zero_rtt)) = self.one_rtt_init_states.get(&peer_id) {
+            // A reply that doesn't prove knowledge of the 0-RTT secret is forged, the pending handshake stays intact
+            let authentic = handshake.auth.is_some_and(|auth| handshake_auth(zero_rtt, &handshake.pk, &handshake.capsule) == auth);
+
+            if !authentic {
+                return Err(NetworkError::InvalidHandshake(peer_id));
+            }
+
+            let ephemeral = sk.shared_from_capsule(&handshake.pk, &handshake.capsule)?;
+            let one_rtt = one_rtt_key(zero_rtt, &ephemeral);
+
+            self.one_rtt_init_states.remove(&peer_id);
+            self.one_rtt_sessions.insert(peer_id, Session::new(one_rtt, true));
+            // Frames are only sealed with the forward-secret key from now on
+            self.zero_rtt_sessions.remove(&peer_id);
+            return Ok(());
+        }
+
+        let Some(identity) = &self.identity else {
+            return Err(NetworkError::NoIdentity);
+        };
+
+        let zero_rtt = identity.sk.shared_from_capsule(&handshake.pk, &handshake.capsule)?;
+
+        let keypair = Kem::random();
+        let (capsule, ephemeral) = keypair.sk.shared(&handshake.pk)?;
+        let one_rtt = one_rtt_key(&zero_rtt, &ephemeral);
+        let auth = handshake_auth(&zero_rtt, &keypair.pk, &capsule).into();
+
+        // A new handshake restarts sessions with the peer
+        self.spent_handshakes.insert(handshake.pk.id(), Instant::now());
+        self.forget_sessions(&peer_id);
+        self.zero_rtt_resp_states.insert(peer_id, zero_rtt);
+        self.one_rtt_resp_states.insert(peer_id, one_rtt);
+
+        let Some(conn) = self.active_conns.get(&conn_id) else {
+            return Err(NetworkError::ConnectionClosed);
+        };
+
+        conn.send(Packet::Handshake(Handshake { pk: keypair.pk, capsule, auth: Some(auth) })).await?;
+
+        Ok(())
+    }
+
+    /// Decrypts a frame preferring the 1-RTT session. The 0-RTT session is dropped once the peer uses the 1-RTT one
+    fn open_frame(&mut self, peer_id: &PeerId, frame: &Frame) -> Result<FrameData, NetworkError> {
+        if let Some(state) = self.one_rtt_resp_states.remove(peer_id) {
+            self.one_rtt_sessions.insert(*peer_id, Session::new(state, false));
+        }
+
+        if let Some(session) = self.one_rtt_sessions.get_mut(peer_id)
+            && let Ok(data) = session.recv(frame)
+        {
+            self.zero_rtt_sessions.remove(peer_id);
+            self.zero_rtt_resp_states.remove(peer_id);
+            return Ok(data);
+        }
+
+        if let Some(state) = self.zero_rtt_resp_states.remove(peer_id) {
+            self.zero_rtt_sessions.insert(*peer_id, Session::new(state, false));
+        }
+
+        match self.zero_rtt_sessions.get_mut(peer_id) {
+            Some(session) => session.recv(frame),
+            None => Err(NetworkError::SessionNotFound(*peer_id)),
+        }
+    }
+
+    fn forget_sessions(&mut self, peer_id: &PeerId) {
+        self.zero_rtt_resp_states.remove(peer_id);
+        self.one_rtt_init_states.remove(peer_id);
+        self.one_rtt_resp_states.remove(peer_id);
+        self.zero_rtt_sessions.remove(peer_id);
+        self.one_rtt_sessions.remove(peer_id);
+    }
+
     async fn select_connection(&mut self, peer_id: &PeerId) -> Result<Channel<Packet>, NetworkError> {
-        todo!("Get or init connection")
+        let active = self.conns_by_peer.get(peer_id)
+            .and_then(|conns| conns.iter().find_map(|conn_id| self.active_conns.get(conn_id)));
+
+        if let Some(conn) = active {
+            return Ok(conn.clone());
+        }
+
+        let Some(peer) = self.get_peer(peer_id) else {
+            return Err(NetworkError::PeerNotFound(*peer_id));
+        };
+
+        let conn = match self.transport.connect(&peer.address).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                self.routing.failed(peer_id);
+                return Err(err);
+            }
+        };
+        let conn_id = self.add_connection(conn, Some(*peer_id));
+
+        Ok(self.active_conns[&conn_id].clone())
+    }
+
+    /// Registers a connection and spawns a task forwarding its packets to the actor
+    fn add_connection(&mut self, conn: Connection, peer_id: Option<PeerId>) -> ConnId {
+        let conn_id = self.next_conn_id;
+        self.next_conn_id += 1;
+
+        let Connection { tx, mut rx } = conn;
+        let packets = self.packets_tx.clone();
+
+        self.active_conns.insert(conn_id, tx);
+        self.connections.spawn(conn_id, async move {
+            while let Some(packet) = rx.recv().await {
+                if let Err(err) = packets.send((conn_id, packet)).await {
+                    return err.into();
+                }
+            }
+
+            NetworkError::ConnectionClosed
+        });
+
+        match peer_id {
+            Some(peer_id) => self.link_connection(conn_id, peer_id),
+            None => { self.pending_conns.insert(conn_id); },
+        }
+
+        conn_id
+    }
+
+    fn link_connection(&mut self, conn_id: ConnId, peer_id: PeerId) {
+        self.pending_conns.remove(&conn_id);
+        self.peer_by_conn.insert(conn_id, peer_id);
+        self.conns_by_peer.entry(peer_id).or_default().insert(conn_id);
     }
 
     fn get_peer(&self, peer_id: &PeerId) -> Option<Peer> {
-        todo!("Get peer by peer_id")
+        self.routing.get(peer_id).or_else(|| self.contacts.get(peer_id)).cloned()
+    }
+
+    /// Banned peers are never selected. Among peers of a bucket the ones with better scores are preferred
+    fn select_peers(&mut self, peer_selection: PeerSelection) -> Vec<PeerId> {
+        match peer_selection {
+            PeerSelection::Closest { target, count } => self.routing.closest(&target, usize::MAX)
+                .into_iter()
+                .filter(|peer_id| !self.reputation.is_banned(peer_id))
+                .take(count as usize)
+                .collect(),
+            PeerSelection::Random { count } => self.routing.peers()
+                .map(|peer| peer.id)
+                .filter(|peer_id| !self.reputation.is_banned(peer_id))
+                .choose_multiple(&mut OsRng, count as usize),
+            PeerSelection::InBucket { bucket, max_count } => {
+                let mut peers = self.routing.in_bucket(&bucket, usize::MAX);
+
+                peers.retain(|peer_id| !self.reputation.is_banned(peer_id));
+                peers.sort_by(|a, b| self.reputation.score(b).total_cmp(&self.reputation.score(a)));
+                peers.truncate(max_count.try_into().unwrap_or(usize::MAX));
+
+                peers
+            },
+            PeerSelection::Peers(peers) => peers.into_iter()
+                .filter(|peer| !self.reputation.is_banned(&peer.id))
+                .map(|peer| {
+                    let peer_id = peer.id;
+
+                    if self.routing.get(&peer_id).is_none() {
+                        self.contacts.insert(peer_id, peer);
+                    }
+
+                    peer_id
+                })
+                .collect(),
+        }
     }
-    fn select_peers(&self, peer_selection: PeerSelection) -> Vec<PeerId> {
-        todo!("Select peers")
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::{error::ServiceError, service::{Service, spawn_service}, sim::{SimNetwork, SimTransport}};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn chunk(data: &'static [u8]) -> FrameData {
+        FrameData::Chunk(Chunk::Data { query_id: 0, bytes: Bytes::from_static(data) })
     }
-}
\ No newline at end of file
+
+    fn chunk_bytes(data: FrameData) -> Bytes {
+        match data {
+            FrameData::Chunk(Chunk::Data { bytes, .. }) => bytes,
+            _ => panic!("unexpected frame data"),
+        }
+    }
+
+    /// Delivers the next packet of the actor to its own handler, returning decrypted frame data
+    async fn deliver(actor: &mut NetworkActor<SimTransport>) -> Option<FrameData> {
+        let (conn_id, packet) = timeout(TIMEOUT, actor.packets_rx.recv()).await.expect("timeout").expect("channel closed");
+
+        match packet {
+            Packet::Handshake(handshake) => {
+                actor.recv_handshake(conn_id, handshake).await.expect("handshake failed");
+                None
+            }
+            Packet::Frame(frame) => {
+                let peer_id = actor.peer_by_conn[&conn_id];
+                Some(actor.open_frame(&peer_id, &frame).expect("open frame failed"))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_upgrade() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), Some(bob_identity));
+
+        alice.routing.insert(bob_peer.clone());
+
+        // Alice sends a handshake and the first frame with the 0-RTT key
+        alice.send(&bob_peer.id, &chunk(b"first")).await.expect("alice send failed");
+        assert!(alice.zero_rtt_sessions.contains_key(&bob_peer.id));
+        assert!(alice.one_rtt_init_states.contains_key(&bob_peer.id));
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+
+        assert!(deliver(&mut bob).await.is_none());
+        assert_eq!(chunk_bytes(deliver(&mut bob).await.expect("no frame")), "first");
+
+        let alice_id = *bob.peer_by_conn.values().next().expect("connection is not linked");
+        assert!(bob.zero_rtt_sessions.contains_key(&alice_id));
+
+        // Bob answers with the 1-RTT key
+        bob.send(&alice_id, &chunk(b"reply")).await.expect("bob send failed");
+        assert!(bob.one_rtt_sessions.contains_key(&alice_id));
+
+        assert!(deliver(&mut alice).await.is_none());
+        assert!(alice.one_rtt_sessions.contains_key(&bob_peer.id));
+        assert!(!alice.zero_rtt_sessions.contains_key(&bob_peer.id), "0-RTT session should be dropped after the upgrade");
+        assert_eq!(chunk_bytes(deliver(&mut alice).await.expect("no frame")), "reply");
+
+        // Alice continues with the 1-RTT key, so Bob drops the 0-RTT session as well
+        alice.send(&bob_peer.id, &chunk(b"second")).await.expect("alice send failed");
+        assert_eq!(chunk_bytes(deliver(&mut bob).await.expect("no frame")), "second");
+        assert!(!bob.zero_rtt_sessions.contains_key(&alice_id), "0-RTT session should be dropped after the upgrade");
+    }
+
+    #[tokio::test]
+    async fn test_forged_handshake_reply() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), Some(bob_identity));
+
+        alice.routing.insert(bob_peer.clone());
+        alice.send(&bob_peer.id, &chunk(b"first")).await.expect("alice send failed");
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+
+        let (conn_id, packet) = bob.packets_rx.recv().await.expect("channel closed");
+        let Packet::Handshake(handshake) = packet else { panic!("expected handshake") };
+
+        // Someone on the path answers Alice's handshake with their own key
+        let attacker = Kem::random();
+        let (capsule, _) = attacker.sk.shared(&handshake.pk).expect("shared failed");
+        let forged = Handshake { pk: attacker.pk, capsule, auth: Some([0; 32]) };
+
+        let alice_conn = *alice.conns_by_peer[&bob_peer.id].iter().next().expect("no connection");
+
+        assert!(matches!(alice.recv_handshake(alice_conn, forged).await, Err(NetworkError::InvalidHandshake(_))));
+        assert!(alice.zero_rtt_sessions.contains_key(&bob_peer.id), "0-RTT session should be kept");
+        assert!(!alice.one_rtt_sessions.contains_key(&bob_peer.id), "forged reply should not start a session");
+
+        // The genuine reply still upgrades the session
+        bob.recv_handshake(conn_id, handshake).await.expect("handshake failed");
+        assert_eq!(chunk_bytes(deliver(&mut bob).await.expect("no frame")), "first");
+
+        let alice_id = *bob.peer_by_conn.values().next().expect("connection is not linked");
+        bob.send(&alice_id, &chunk(b"reply")).await.expect("bob send failed");
+
+        assert!(deliver(&mut alice).await.is_none());
+        assert_eq!(chunk_bytes(deliver(&mut alice).await.expect("no frame")), "reply");
+        assert!(alice.one_rtt_sessions.contains_key(&bob_peer.id));
+    }
+
+    #[tokio::test]
+    async fn test_replayed_handshake() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), Some(bob_identity));
+
+        alice.routing.insert(bob_peer.clone());
+        alice.send(&bob_peer.id, &chunk(b"first")).await.expect("alice send failed");
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+
+        let (conn_id, packet) = bob.packets_rx.recv().await.expect("channel closed");
+        let Packet::Handshake(handshake) = packet else { panic!("expected handshake") };
+
+        bob.recv_handshake(conn_id, handshake.clone()).await.expect("handshake failed");
+        assert_eq!(chunk_bytes(deliver(&mut bob).await.expect("no frame")), "first");
+
+        let alice_id = bob.peer_by_conn[&conn_id];
+
+        // Someone who captured the handshake replays it on the same connection and on their own
+        assert!(matches!(bob.recv_handshake(conn_id, handshake.clone()).await, Err(NetworkError::InvalidHandshake(_))));
+
+        let _mallory = sim.transport("mallory").connect("bob").await.expect("connect failed");
+        let mallory_conn = bob.add_connection(listener.recv().await.expect("no connection"), None);
+
+        assert!(matches!(bob.recv_handshake(mallory_conn, handshake).await, Err(NetworkError::InvalidHandshake(_))));
+        assert!(!bob.peer_by_conn.contains_key(&mallory_conn), "replayed handshake should not link the connection");
+        assert_eq!(bob.conns_by_peer[&alice_id].len(), 1);
+
+        // Alice's sessions are intact
+        alice.send(&bob_peer.id, &chunk(b"second")).await.expect("alice send failed");
+        assert_eq!(chunk_bytes(deliver(&mut bob).await.expect("no frame")), "second");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_requires_identity() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), None);
+
+        alice.routing.insert(bob_peer.clone());
+        alice.send(&bob_peer.id, &chunk(b"first")).await.expect("alice send failed");
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+
+        let (conn_id, packet) = bob.packets_rx.recv().await.expect("channel closed");
+        let Packet::Handshake(handshake) = packet else { panic!("expected handshake") };
+
+        assert!(matches!(bob.recv_handshake(conn_id, handshake).await, Err(NetworkError::NoIdentity)));
+    }
+
+    fn query(bytes: &'static [u8]) -> Query {
+        Query { bytes: Bytes::from_static(bytes), service_id: 0, method_id: 0, query_id: 0, stamp: None, stream: None }
+    }
+
+    #[tokio::test]
+    async fn test_query_replies() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), Some(bob_identity));
+
+        alice.routing.insert(bob_peer.clone());
+
+        let (callback, replies) = Callback::new();
+        alice.start_query(vec![(bob_peer.id, query(b"ping"))], TIMEOUT, callback).await;
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+        assert!(deliver(&mut bob).await.is_none());
+
+        let Some(FrameData::Query(received)) = deliver(&mut bob).await else { panic!("expected query") };
+        assert_eq!(received.bytes, "ping");
+
+        let alice_id = *bob.peer_by_conn.values().next().expect("connection is not linked");
+        let reply = Reply::ok(&received, Bytes::from_static(b"pong"));
+
+        // A duplicate reply is ignored
+        bob.send(&alice_id, &FrameData::Reply(reply.clone())).await.expect("bob send failed");
+        bob.send(&alice_id, &FrameData::Reply(reply)).await.expect("bob send failed");
+
+        assert!(deliver(&mut alice).await.is_none());
+
+        for _ in 0..2 {
+            let Some(FrameData::Reply(reply)) = deliver(&mut alice).await else { panic!("expected reply") };
+            alice.recv_reply(&bob_peer.id, reply);
+        }
+
+        let replies = timeout(TIMEOUT, replies).await.expect("timeout").expect("callback dropped").expect("query failed");
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, bob_peer.id);
+        assert_eq!(replies[0].1.bytes, "pong");
+        assert!(alice.queries.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_timeout() {
+        let sim = SimNetwork::new(0);
+        let _listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        alice.routing.insert(bob_peer.clone());
+
+        let (callback, replies) = Callback::new();
+        alice.start_query(vec![(bob_peer.id, query(b"ping"))], Duration::from_secs(3), callback).await;
+
+        // Bob never answers, so the deadline returns an empty set of replies
+        let deadline = alice.query_deadlines.join_next().await.expect("no deadline");
+        alice.expire_query(deadline).await;
+
+        let replies = replies.await.expect("callback dropped").expect("query failed");
+        assert!(replies.is_empty());
+        assert!(alice.queries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_unreachable() {
+        let sim = SimNetwork::new(0);
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        alice.routing.insert(bob_peer.clone());
+
+        let (callback, replies) = Callback::new();
+        alice.start_query(vec![(bob_peer.id, query(b"ping"))], TIMEOUT, callback).await;
+
+        assert!(matches!(replies.await.expect("callback dropped"), Err(NetworkError::Io(_))));
+    }
+
+    /// Replies with the reversed query bytes to method 1
+    struct Reverse;
+
+    impl Service for Reverse {
+        async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            match incoming.query.method_id {
+                1 => Ok(incoming.query.bytes.iter().rev().copied().collect()),
+                method_id => Err(ServiceError::UnknownMethod(method_id)),
+            }
+        }
+    }
+
+    /// Streams three chunks
+    struct Letters;
+
+    impl Service for Letters {
+        async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            Err(ServiceError::UnknownMethod(incoming.query.method_id))
+        }
+
+        async fn handle_stream(&mut self, _incoming: IncomingQuery) -> Result<ChunkStream, ServiceError> {
+            Ok(Box::pin(tokio_stream::iter([b"a", b"b", b"c"].map(|letter| Bytes::from_static(letter)))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_zero_window() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), Some(bob_identity));
+
+        alice.routing.insert(bob_peer.clone());
+        bob.services.insert(7, spawn_service(Letters));
+
+        // A requester that allows no chunks at all
+        let query = Query { bytes: Bytes::new(), service_id: 7, method_id: 1, query_id: 0, stamp: None, stream: Some(0) };
+        alice.send(&bob_peer.id, &FrameData::Query(query)).await.expect("alice send failed");
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+        assert!(deliver(&mut bob).await.is_none());
+
+        let Some(FrameData::Query(received)) = deliver(&mut bob).await else { panic!("expected query") };
+        let alice_id = *bob.peer_by_conn.values().next().expect("connection is not linked");
+
+        bob.recv_query(alice_id, received).await;
+        let opened = timeout(TIMEOUT, bob.opening_streams.join_next()).await.expect("timeout").expect("no stream opened");
+        bob.start_stream(opened).await;
+
+        let key = (alice_id, 0);
+        assert_eq!(bob.stream_credits[&key], 1, "window should be raised to one chunk");
+
+        // The stream is parked after a single chunk instead of running without flow control
+        let chunk = timeout(TIMEOUT, bob.outgoing_streams.next()).await.expect("timeout").expect("no chunk");
+        bob.send_chunk(chunk).await;
+
+        assert_eq!(bob.stream_credits[&key], 0);
+        assert!(bob.parked_streams.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_query_dispatch() {
+        let sim = SimNetwork::new(0);
+        let mut listener = sim.listen("bob");
+
+        let bob_identity = Kem::random();
+        let bob_peer = Peer { id: bob_identity.pk.id(), pk: bob_identity.pk.clone(), address: "bob".to_string() };
+
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+        let mut bob = NetworkActor::new(sim.transport("bob"), Some(bob_identity));
+
+        alice.routing.insert(bob_peer.clone());
+        bob.services.insert(7, spawn_service(Reverse));
+
+        let (callback, replies) = Callback::new();
+        let query = Query { bytes: Bytes::from_static(b"ping"), service_id: 7, method_id: 1, query_id: 0, stamp: None, stream: None };
+        alice.start_query(vec![(bob_peer.id, query)], TIMEOUT, callback).await;
+
+        bob.add_connection(listener.recv().await.expect("no connection"), None);
+        assert!(deliver(&mut bob).await.is_none());
+
+        let Some(FrameData::Query(received)) = deliver(&mut bob).await else { panic!("expected query") };
+        let alice_id = *bob.peer_by_conn.values().next().expect("connection is not linked");
+
+        bob.recv_query(alice_id, received).await;
+        let handled = timeout(TIMEOUT, bob.handling.join_next()).await.expect("timeout").expect("no query handled");
+        bob.send_reply(handled).await;
+
+        assert!(deliver(&mut alice).await.is_none());
+        let Some(FrameData::Reply(reply)) = deliver(&mut alice).await else { panic!("expected reply") };
+        alice.recv_reply(&bob_peer.id, reply);
+
+        let replies = timeout(TIMEOUT, replies).await.expect("timeout").expect("callback dropped").expect("query failed");
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1.bytes, "gnip");
+
+        // Queries to services the node doesn't run are answered right away
+        let (callback, replies) = Callback::new();
+        let query = Query { bytes: Bytes::from_static(b"ping"), service_id: 9, method_id: 1, query_id: 0, stamp: None, stream: None };
+        alice.start_query(vec![(bob_peer.id, query)], TIMEOUT, callback).await;
+
+        let Some(FrameData::Query(received)) = deliver(&mut bob).await else { panic!("expected query") };
+        bob.recv_query(alice_id, received).await;
+
+        let Some(FrameData::Reply(reply)) = deliver(&mut alice).await else { panic!("expected reply") };
+        alice.recv_reply(&bob_peer.id, reply);
+
+        let replies = timeout(TIMEOUT, replies).await.expect("timeout").expect("callback dropped").expect("query failed");
+        assert_eq!(replies[0].1.status, ReplyStatus::UnknownService);
+    }
+
+    #[tokio::test]
+    async fn test_select_peers_by_reputation() {
+        let sim = SimNetwork::new(0);
+        let mut alice = NetworkActor::new(sim.transport("alice"), None);
+
+        let peers: Vec<Peer> = (0..3).map(|i| {
+            let pk = Kem::random().pk;
+            Peer { id: pk.id(), pk, address: format!("peer-{i}") }
+        }).collect();
+
+        for peer in peers.iter() {
+            alice.routing.insert(peer.clone());
+        }
+
+        let (liar, honest, unknown) = (peers[0].id, peers[1].id, peers[2].id);
+
+        alice.reputation.record(honest, true);
+        while !alice.reputation.is_banned(&liar) {
+            alice.reputation.record(liar, false);
+        }
+
+        let selected = alice.select_peers(PeerSelection::InBucket { bucket: Id::default(), max_count: 3 });
+        assert_eq!(selected, vec![honest, unknown]);
+
+        let selected = alice.select_peers(PeerSelection::Random { count: 3 });
+        assert!(!selected.contains(&liar));
+    }
+}
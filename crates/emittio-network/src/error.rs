@@ -2,7 +2,7 @@ use emittio_crypto::error::CryptoError;
 use actorify::ChannelError;
 use thiserror::Error;
 
-use crate::peer::PeerId;
+use crate::{peer::PeerId, reply::ReplyStatus};
 
 #[derive(Debug, Error)]
 pub enum NetworkError {
@@ -20,4 +20,51 @@ pub enum NetworkError {
 
     #[error("peer not found: {0:?}")]
     PeerNotFound(PeerId),
-}
\ No newline at end of file
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("packet too large: {0} bytes")]
+    PacketTooLarge(usize),
+
+    #[error("connection closed")]
+    ConnectionClosed,
+
+    #[error("no session with peer: {0:?}")]
+    SessionNotFound(PeerId),
+
+    #[error("static identity required to answer handshakes")]
+    NoIdentity,
+
+    #[error("handshake reply is not authenticated by the peer: {0:?}")]
+    InvalidHandshake(PeerId),
+
+    #[error("stream idle for too long")]
+    StreamTimeout,
+
+    #[error(transparent)]
+    Reply(#[from] ReplyError),
+}
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    #[error("unknown method: {0}")]
+    UnknownMethod(u16),
+
+    #[error("rate limited")]
+    RateLimited,
+
+    #[error("insufficient proof of work")]
+    InsufficientPow,
+
+    #[error(transparent)]
+    Postcard(#[from] postcard::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum ReplyError {
+    #[error("remote error: {0:?}")]
+    Remote(ReplyStatus),
+
+    #[error(transparent)]
+    Decode(#[from] postcard::Error),
+}
@@ -2,8 +2,19 @@ pub mod session;
 pub mod error;
 pub mod types;
 pub mod peer;
+pub mod routing;
+pub mod lookup;
+pub mod reputation;
+pub mod pow;
+pub mod registry;
 pub mod actor;
 pub mod transport;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
 pub mod query;
 pub mod reply;
 pub mod verifier;
+pub mod service;
+pub mod stream;
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashSet};
+
+use emittio_crypto::id::Id;
+use serde::{Deserialize, Serialize};
+
+use crate::{actor::NetworkActorHandle, error::NetworkError, peer::Peer, query::{PeerSelection, Query, Queryable}, routing::{K, distance}};
+
+/// Service implemented by the network actor itself
+pub const NETWORK_SERVICE_ID: u16 = 0;
+
+/// How many peers are asked in parallel during a lookup round
+pub const ALPHA: usize = 3;
+
+crate::queries!(pub QUERIES = [FindNode]);
+
+/// Asks a peer for the `K` peers closest to `target` it knows about
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FindNode {
+    pub target: Id,
+}
+
+impl Queryable for FindNode {
+    const SERVICE_ID: u16 = NETWORK_SERVICE_ID;
+    const METHOD_ID: u16 = 1;
+
+    type Reply = Vec<Peer>;
+
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Closest { target: self.target, count: K as u16 }
+    }
+}
+
+/// Finds up to `count` responsive peers closest to `target` across the network.
+///
+/// Starts from the closest peers of the local routing table and repeatedly asks `ALPHA` of the closest not yet asked peers
+/// for closer ones, until all of the `count` closest known peers have been asked. Peers that answer are added to the routing table
+pub async fn lookup(network: &NetworkActorHandle, target: Id, count: usize) -> Result<Vec<Peer>, NetworkError> {
+    let local_id = network.local_id().await?;
+
+    let mut shortlist: BTreeMap<[u8; 32], Peer> = network.closest_peers(target, K as u16).await?
+        .into_iter()
+        .map(|peer| (distance(&peer.id, &target).0, peer))
+        .collect();
+    let mut queried = HashSet::new();
+
+    let query = Query {
+        bytes: postcard::to_stdvec(&FindNode { target })?.into(),
+        service_id: FindNode::SERVICE_ID,
+        method_id: FindNode::METHOD_ID,
+        query_id: 0, // network actor chooses it
+        stamp: None,
+        stream: None,
+    };
+
+    loop {
+        let round: Vec<Peer> = shortlist.values()
+            .take(count)
+            .filter(|peer| !queried.contains(&peer.id))
+            .take(ALPHA)
+            .cloned()
+            .collect();
+
+        if round.is_empty() {
+            break;
+        }
+
+        queried.extend(round.iter().map(|peer| peer.id));
+
+        let replies = network.query(PeerSelection::Peers(round.clone()), query.clone(), FindNode::timeout()).await??;
+        let answered: HashSet<_> = replies.iter().map(|(peer_id, _)| *peer_id).collect();
+
+        for peer in round {
+            if answered.contains(&peer.id) {
+                network.add_peer(peer).await?;
+            } else {
+                // Unresponsive peers can't be responsible for the target
+                shortlist.remove(&distance(&peer.id, &target).0);
+            }
+        }
+
+        for (_, reply) in replies {
+            let Ok(peers) = reply.parse::<Vec<Peer>>() else {
+                continue;
+            };
+
+            for peer in peers.into_iter().take(K) {
+                // Ids must be derived from keys, otherwise a peer could place itself anywhere in the id space
+                if peer.id != peer.pk.id() || peer.id == local_id || queried.contains(&peer.id) {
+                    continue;
+                }
+
+                shortlist.entry(distance(&peer.id, &target).0).or_insert(peer);
+            }
+        }
+    }
+
+    Ok(shortlist.into_values().take(count).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::sim::SimNetwork;
+
+    use super::*;
+
+    const NODES: usize = 64;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let sim = SimNetwork::new(0);
+
+        let nodes: Vec<_> = (0..NODES).map(|i| sim.spawn_node(&format!("node-{i}"))).collect();
+
+        // Full buckets drop most of the far peers, so every node only knows its own neighbourhood well
+        for (handle, _) in nodes.iter() {
+            for (_, peer) in nodes.iter() {
+                handle.add_peer(peer.clone()).await.expect("add peer failed");
+            }
+        }
+
+        let client = sim.spawn_client("client");
+        client.add_peer(nodes[0].1.clone()).await.expect("add peer failed");
+
+        let target = Id::hash_bytes(b"target");
+
+        let mut expected: Vec<Id> = nodes.iter().map(|(_, peer)| peer.id).collect();
+        expected.sort_by_key(|id| distance(id, &target).0);
+        expected.truncate(5);
+
+        let found = timeout(TIMEOUT, lookup(&client, target, 5)).await.expect("timeout").expect("lookup failed");
+
+        assert_eq!(found.iter().map(|peer| peer.id).collect::<Vec<_>>(), expected);
+    }
+}
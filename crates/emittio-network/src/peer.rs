@@ -1,8 +1,9 @@
 use emittio_crypto::{id::Id, kem::PublicKey};
+use serde::{Deserialize, Serialize};
 
 pub type PeerId = Id;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub id: PeerId,
     pub pk: PublicKey,
@@ -0,0 +1,256 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{peer::{Peer, PeerId}, query::Query};
+
+/// How far the time of a stamp may drift from the receiver's clock
+pub const STAMP_TTL: Duration = Duration::from_secs(10 * 60);
+
+const DOMAIN: &[u8] = b"emittio-pow-stamp";
+
+/// Amount of work a query must carry to be handled
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum PowConfig {
+    #[default]
+    None,
+    Low,
+    Medium,
+    High,
+}
+
+impl PowConfig {
+    /// Number of leading zero bits the stamp hash must have. Every bit doubles the expected work
+    pub fn difficulty(&self) -> u32 {
+        match self {
+            PowConfig::None => 0,
+            PowConfig::Low => 8,
+            PowConfig::Medium => 14,
+            PowConfig::High => 20,
+        }
+    }
+}
+
+/// Proof of work bound to the query content, the receiving peer and the time it was made,
+/// so it can't be reused for another query, another peer, or replayed later
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    /// Unix time in seconds
+    pub issued_at: u64,
+    pub nonce: u64,
+}
+
+impl Stamp {
+    /// Finds a stamp of `pow` level for `query` sent to `peer_id`. CPU-bound, run it off the async runtime
+    pub fn solve(query: &Query, peer_id: &PeerId, pow: PowConfig) -> Self {
+        Self::solve_at(query, peer_id, pow, current_time())
+    }
+
+    fn solve_at(query: &Query, peer_id: &PeerId, pow: PowConfig, issued_at: u64) -> Self {
+        let hasher = prefix(query, peer_id, issued_at);
+        // Identical queries sent within a second must not get the same stamp, it would be rejected as a replay
+        let start: u64 = rand::random();
+
+        let nonce = (0..)
+            .map(|i| start.wrapping_add(i))
+            .find(|nonce| leading_zeros(&hasher, *nonce) >= pow.difficulty())
+            .expect("nonce space exhausted");
+
+        Self { issued_at, nonce }
+    }
+
+    /// Checks the stamp against `query` received by `peer_id`
+    pub fn verify(&self, query: &Query, peer_id: &PeerId, pow: PowConfig) -> bool {
+        let now = current_time();
+
+        if self.issued_at.abs_diff(now) > STAMP_TTL.as_secs() {
+            return false;
+        }
+
+        leading_zeros(&prefix(query, peer_id, self.issued_at), self.nonce) >= pow.difficulty()
+    }
+}
+
+/// Checks that `query` received by `peer_id` carries enough work for `pow`
+pub fn satisfies(query: &Query, peer_id: &PeerId, pow: PowConfig) -> bool {
+    match (pow, &query.stamp) {
+        (PowConfig::None, _) => true,
+        (_, Some(stamp)) => stamp.verify(query, peer_id, pow),
+        (_, None) => false,
+    }
+}
+
+/// Stamps this node has accepted until they expire, so a stamped query can't be replayed to it within `STAMP_TTL`.
+/// Clones share the same stamps, services record them once they have verified them
+#[derive(Clone, Default)]
+pub struct SeenStamps {
+    /// Expiry time of every stamp by its hash
+    seen: Arc<Mutex<HashMap<blake3::Hash, u64>>>,
+}
+
+impl SeenStamps {
+    /// Records the stamp of `query` received by `peer_id`, returning `false` if it was seen before.
+    /// Queries without a stamp are never replays. Only verified stamps should be recorded, others could never expire
+    pub fn insert(&self, query: &Query, peer_id: &PeerId) -> bool {
+        let Some(stamp) = &query.stamp else {
+            return true;
+        };
+
+        let hash = prefix(query, peer_id, stamp.issued_at).update(&stamp.nonce.to_be_bytes()).finalize();
+
+        self.seen().insert(hash, stamp.issued_at.saturating_add(STAMP_TTL.as_secs())).is_none()
+    }
+
+    /// Forgets stamps that are too old to pass verification anyway
+    pub fn prune(&self) {
+        let now = current_time();
+
+        self.seen().retain(|_, expires_at| *expires_at >= now);
+    }
+
+    fn seen(&self) -> std::sync::MutexGuard<'_, HashMap<blake3::Hash, u64>> {
+        self.seen.lock().expect("seen stamps poisoned")
+    }
+}
+
+/// Checks the work of `query` received by `peer_id` like [`satisfies`] and spends its stamp, so it is accepted once only
+pub fn accept(query: &Query, peer_id: &PeerId, pow: PowConfig, seen: &SeenStamps) -> bool {
+    // Without required work nothing is spent, and an unverified stamp is not recorded
+    pow == PowConfig::None || (satisfies(query, peer_id, pow) && seen.insert(query, peer_id))
+}
+
+/// Copies `query` for every peer, stamping each copy for its receiver
+pub async fn stamp_for(query: Query, peers: Vec<Peer>, pow: PowConfig) -> Vec<(Peer, Query)> {
+    if pow == PowConfig::None {
+        return peers.into_iter().map(|peer| (peer, query.clone())).collect();
+    }
+
+    tokio::task::spawn_blocking(move || {
+        peers.into_iter()
+            .map(|peer| {
+                let mut query = query.clone();
+                query.stamp = Some(Stamp::solve(&query, &peer.id, pow));
+                (peer, query)
+            })
+            .collect()
+    }).await.expect("stamping task panicked")
+}
+
+fn prefix(query: &Query, peer_id: &PeerId, issued_at: u64) -> blake3::Hasher {
+    let mut hasher = blake3::Hasher::new();
+
+    hasher.update(DOMAIN);
+    hasher.update(&peer_id.0);
+    hasher.update(&query.service_id.to_be_bytes());
+    hasher.update(&query.method_id.to_be_bytes());
+    hasher.update(&(query.bytes.len() as u64).to_be_bytes());
+    hasher.update(&query.bytes);
+    hasher.update(&issued_at.to_be_bytes());
+
+    hasher
+}
+
+fn leading_zeros(prefix: &blake3::Hasher, nonce: u64) -> u32 {
+    let hash = prefix.clone().update(&nonce.to_be_bytes()).finalize();
+
+    let mut zeros = 0;
+    for byte in hash.as_bytes() {
+        zeros += byte.leading_zeros();
+
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    zeros
+}
+
+pub(crate) fn current_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock may have gone backwards")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use emittio_crypto::id::Id;
+
+    use super::*;
+
+    fn query() -> Query {
+        Query { bytes: Bytes::from_static(b"pointer"), service_id: 1, method_id: 3, query_id: 0, stamp: None, stream: None }
+    }
+
+    #[test]
+    fn test_stamp() {
+        let peer = Id([1; 32]);
+        let mut query = query();
+
+        assert!(satisfies(&query, &peer, PowConfig::None));
+        assert!(!satisfies(&query, &peer, PowConfig::Low), "missing stamp should be rejected");
+
+        query.stamp = Some(Stamp::solve(&query, &peer, PowConfig::Medium));
+        assert!(satisfies(&query, &peer, PowConfig::Low));
+        assert!(satisfies(&query, &peer, PowConfig::Medium));
+
+        assert!(!satisfies(&query, &Id([2; 32]), PowConfig::Medium), "stamp is bound to the receiver");
+
+        let mut other = query.clone();
+        other.bytes = Bytes::from_static(b"another pointer");
+        assert!(!satisfies(&other, &peer, PowConfig::Medium), "stamp is bound to the query bytes");
+    }
+
+    #[test]
+    fn test_stale_stamp() {
+        let peer = Id([1; 32]);
+        let mut query = query();
+
+        query.stamp = Some(Stamp::solve_at(&query, &peer, PowConfig::Low, current_time() - STAMP_TTL.as_secs() - 1));
+
+        assert!(!satisfies(&query, &peer, PowConfig::Low));
+    }
+
+    #[test]
+    fn test_seen_stamps() {
+        let peer = Id([1; 32]);
+        let mut query = query();
+        let seen = SeenStamps::default();
+
+        assert!(seen.insert(&query, &peer) && seen.insert(&query, &peer), "unstamped queries are not replays");
+
+        query.stamp = Some(Stamp::solve(&query, &peer, PowConfig::Low));
+        assert!(seen.insert(&query, &peer));
+        assert!(!seen.insert(&query, &peer), "replayed stamp should be rejected");
+
+        seen.prune();
+        assert!(!seen.insert(&query, &peer), "stamp should be kept until it expires");
+
+        query.stamp = Some(Stamp::solve(&query, &peer, PowConfig::Low));
+        assert!(seen.insert(&query, &peer), "the same query can be stamped again");
+
+        query.stamp = Some(Stamp::solve_at(&query, &peer, PowConfig::Low, current_time() - STAMP_TTL.as_secs() - 1));
+        assert!(seen.insert(&query, &peer));
+
+        seen.prune();
+        assert_eq!(seen.seen().len(), 2, "expired stamp should be forgotten");
+    }
+
+    #[test]
+    fn test_accept() {
+        let peer = Id([1; 32]);
+        let mut query = query();
+        let seen = SeenStamps::default();
+
+        query.stamp = Some(Stamp::solve(&query, &peer, PowConfig::Low));
+        assert!(accept(&query, &peer, PowConfig::Low, &seen));
+        assert!(!accept(&query, &peer, PowConfig::Low, &seen), "stamp should be spent");
+
+        // A stamp that never expires and carries no work
+        query.stamp = Some(Stamp { issued_at: u64::MAX, nonce: 0 });
+        assert!(!accept(&query, &peer, PowConfig::Low, &seen));
+        assert!(accept(&query, &peer, PowConfig::None, &seen));
+        assert_eq!(seen.seen().len(), 1, "unverified stamps should not be recorded");
+    }
+}
@@ -1,13 +1,18 @@
-use bytes::{Bytes, BytesMut};
+use std::{collections::HashSet, time::Duration};
+
+use bytes::Bytes;
 use emittio_crypto::id::Id;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-use crate::{actor::NetworkActorHandle, error::NetworkError, reply::Replyable, verifier::{NoVerifier, Verifier}};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{actor::NetworkActorHandle, error::{NetworkError, ReplyError}, lookup::lookup, peer::{Peer, PeerId}, pow::{PowConfig, Stamp, stamp_for}, reply::{ReplyStatus, Replyable}, stream::STREAM_WINDOW, verifier::{NoVerifier, Verifier}};
 
 /// Describes how to select peers
 #[derive(Clone)]
 pub enum PeerSelection {
-    /// Selects N closest peers to the `target`
+    /// Selects N closest peers to the `target`. `Queryable::query` finds them across the network with [`lookup`](crate::lookup::lookup),
+    /// otherwise they are taken from the routing table
     Closest {
         target: Id,
         count: u16,
@@ -21,8 +26,12 @@ pub enum PeerSelection {
         bucket: Id,
         max_count: u64,
     },
+    /// Selects exactly these peers. They can be dialed even if they are not in the routing table
+    Peers(Vec<Peer>),
 }
 
+pub type QueryId = u16;
+
 /// Network-level query struct with a data in raw bytes and configs about its destination and verification
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Query {
@@ -33,9 +42,14 @@ pub struct Query {
     /// Query type
     pub method_id: u16,
     /// Identifies a single query instance. Used to match query with a reply
-    pub query_id: u16,
+    pub query_id: QueryId,
+    /// Proof of work for the receiving peer. Required by services of queries with a `PowConfig` other than `None`
+    pub stamp: Option<Stamp>,
+    /// Set for streamed queries to the number of chunks the responder may send before waiting for more credit
+    pub stream: Option<u32>,
 }
 
+/// Typed query. `(SERVICE_ID, METHOD_ID)` pairs must be unique, see [`queries!`](crate::queries)
 pub trait Queryable: Serialize + DeserializeOwned {
     const SERVICE_ID: u16;
     const METHOD_ID: u16;
@@ -52,33 +66,123 @@ pub trait Queryable: Serialize + DeserializeOwned {
     fn retries() -> u8 {
         3
     }
+    /// How long to wait for replies of the selected peers
+    fn timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+    /// How much work the handler requires to accept the query
+    fn pow(&self) -> PowConfig {
+        PowConfig::None
+    }
 
-    /// Send the query through a network handle
-    fn query(&self, network: &NetworkActorHandle) -> impl Future<Output = Result<Option<Self::Reply>, NetworkError>> {
+    /// Reply standing for a peer that didn't handle the query with `status`, so the verifier can tell such peers apart.
+    /// By default their replies are skipped
+    fn status_reply(_status: ReplyStatus) -> Option<Self::Reply> {
+        None
+    }
+
+    /// How many chunks of a streamed reply a peer may send ahead
+    fn stream_window() -> u32 {
+        STREAM_WINDOW
+    }
+
+    /// Sends the query to `peer` and streams the reply, decoding every chunk as `Self::Reply`.
+    /// Only works for queries the peer's service handles with a [`StreamHandler`](crate::service::StreamHandler)
+    fn query_stream(&self, network: &NetworkActorHandle, peer: Peer) -> impl Future<Output = Result<impl Stream<Item = Result<Self::Reply, NetworkError>> + use<Self>, NetworkError>> {
         async move {
-            let mut bytes = BytesMut::new();
+            let q = Query {
+                bytes: postcard::to_stdvec(self)?.into(),
+                service_id: Self::SERVICE_ID,
+                method_id: Self::METHOD_ID,
+                query_id: 0, // network actor chooses it
+                stamp: None,
+                stream: None, // network actor sets the window
+            };
 
-            postcard::to_slice(self, &mut bytes[..])?;
+            let (peer, q) = stamp_for(q, vec![peer], self.pow()).await.pop().expect("one query per peer");
+            let chunks = network.query_stream(peer, q, Self::stream_window()).await?;
 
+            Ok(chunks.map(|chunk| Ok(postcard::from_bytes(&chunk?)?)))
+        }
+    }
+
+    /// Sends the query to each of `peers` once, without verification or retries,
+    /// returning the replies of the peers that answered with a decodable `Self::Reply`
+    fn query_each(&self, network: &NetworkActorHandle, peers: Vec<Peer>) -> impl Future<Output = Result<Vec<(PeerId, Self::Reply)>, NetworkError>> {
+        async move {
+            let q = Query {
+                bytes: postcard::to_stdvec(self)?.into(),
+                service_id: Self::SERVICE_ID,
+                method_id: Self::METHOD_ID,
+                query_id: 0, // network actor chooses it
+                stamp: None, // each peer gets its own
+                stream: None,
+            };
+
+            let queries = stamp_for(q, peers, self.pow()).await;
+
+            Ok(network.query_peers(queries, Self::timeout()).await??
+                .into_iter()
+                .filter(|(_, reply)| reply.service_id == Self::SERVICE_ID && reply.method_id == Self::METHOD_ID)
+                .filter_map(|(peer_id, reply)| match reply.parse() {
+                    Ok(reply) => Some((peer_id, reply)),
+                    Err(ReplyError::Remote(status)) => Some((peer_id, Self::status_reply(status)?)),
+                    Err(ReplyError::Decode(_)) => None,
+                })
+                .collect())
+        }
+    }
+
+    /// Send the query through a network handle
+    fn query(&self, network: &NetworkActorHandle) -> impl Future<Output = Result<Option<Self::Reply>, NetworkError>> {
+        async move {
             let q = Query {
-                bytes: bytes.freeze(),
+                bytes: postcard::to_stdvec(self)?.into(),
                 service_id: Self::SERVICE_ID,
                 method_id: Self::METHOD_ID,
                 query_id: 0, // network actor chooses it
+                stamp: None, // each peer gets its own
+                stream: None,
             };
 
             let verifier = self.verifier();
             let peer_selection = self.peer_selection();
+            let pow = self.pow();
 
             let mut retries = Self::retries();
+            let mut asked = HashSet::new();
 
             loop {
-                let replies = network.query(peer_selection.clone(), q.clone()).await??
-                    .into_iter()
-                    .filter_map(|(id, r)| r.parse().ok().map(|r| (id, r)))
-                    .collect();
+                let peers = match &peer_selection {
+                    // Retries go to the next closest peers, the ones asked already had no valid reply
+                    PeerSelection::Closest { target, count } => lookup(network, *target, asked.len() + *count as usize).await?
+                        .into_iter()
+                        .filter(|peer| !asked.contains(&peer.id))
+                        .collect(),
+                    peer_selection => network.select(peer_selection.clone()).await?,
+                };
+                asked.extend(peers.iter().map(|peer| peer.id));
+                let queries = stamp_for(q.clone(), peers, pow).await;
+
+                let mut results = Vec::new();
+                let mut replies = Vec::new();
+
+                for (peer_id, reply) in network.query_peers(queries, Self::timeout()).await?? {
+                    if reply.service_id != Self::SERVICE_ID || reply.method_id != Self::METHOD_ID {
+                        results.push((peer_id, false));
+                        continue;
+                    }
+
+                    match reply.parse() {
+                        Ok(reply) => replies.push((peer_id, reply)),
+                        // A peer that can't or won't handle the query isn't misbehaving
+                        Err(ReplyError::Remote(status)) => replies.extend(Self::status_reply(status).map(|reply| (peer_id, reply))),
+                        Err(ReplyError::Decode(_)) => results.push((peer_id, false)),
+                    }
+                }
 
-                let (results, reply) = verifier.verify(replies);
+                let (verified, reply) = verifier.verify(replies);
+                results.extend(verified);
 
                 network.verifications(results).await?;
 
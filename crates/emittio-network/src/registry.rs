@@ -0,0 +1,76 @@
+/// Declares a list of query types as a `&[(u16, u16)]` constant of their `(SERVICE_ID, METHOD_ID)` pairs
+/// and fails to compile if two of them share a pair
+///
+/// ```ignore
+/// queries!(pub QUERIES = [CountPointers, GetPointers, PutPointer]);
+/// ```
+#[macro_export]
+macro_rules! queries {
+    ($vis:vis $name:ident = [$($query:ty),* $(,)?]) => {
+        $vis const $name: &[(u16, u16)] = &[
+            $((<$query as $crate::query::Queryable>::SERVICE_ID, <$query as $crate::query::Queryable>::METHOD_ID)),*
+        ];
+
+        const _: () = $crate::registry::assert_unique(&[$name]);
+    };
+}
+
+/// Panics if a `(SERVICE_ID, METHOD_ID)` pair appears twice across `registries`.
+/// Evaluate it in a constant to check registries of different crates at compile time:
+///
+/// ```ignore
+/// const _: () = assert_unique(&[emittio_pointer::QUERIES, emittio_dht::QUERIES]);
+/// ```
+pub const fn assert_unique(registries: &[&[(u16, u16)]]) {
+    let mut i = 0;
+
+    while i < registries.len() {
+        let mut j = 0;
+
+        while j < registries[i].len() {
+            let (service_id, method_id) = registries[i][j];
+
+            // Compare with every pair after this one
+            let mut k = i;
+            let mut l = j + 1;
+
+            while k < registries.len() {
+                while l < registries[k].len() {
+                    if registries[k][l].0 == service_id && registries[k][l].1 == method_id {
+                        panic!("duplicate (SERVICE_ID, METHOD_ID) pair");
+                    }
+                    l += 1;
+                }
+
+                k += 1;
+                l = 0;
+            }
+
+            j += 1;
+        }
+
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unique() {
+        assert_unique(&[&[(1, 1), (1, 2)], &[(2, 1)], &[]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate")]
+    fn test_duplicate_in_one_registry() {
+        assert_unique(&[&[(1, 1), (1, 1)]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate")]
+    fn test_duplicate_across_registries() {
+        assert_unique(&[&[(1, 1), (1, 2)], &[(2, 1), (1, 2)]]);
+    }
+}
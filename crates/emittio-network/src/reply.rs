@@ -1,14 +1,90 @@
-// TODO: implement `Reply` and `Replyable`
+use bytes::Bytes;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-use serde::{Serialize, de::DeserializeOwned};
+use crate::{error::{ReplyError, ServiceError}, query::{Query, QueryId}};
 
-pub struct Reply {}
-pub trait Replyable {}
+/// Outcome of handling a query on the remote side
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyStatus {
+    /// The query was handled, reply bytes contain the encoded reply
+    Ok,
+    /// The peer doesn't run the requested service
+    UnknownService,
+    /// The service failed to decode or handle the query
+    HandlerError,
+    /// The peer refused to handle the query because of too many requests
+    RateLimited,
+    /// The query's proof of work is missing, stale or too weak
+    InsufficientPow,
+}
+
+impl From<&ServiceError> for ReplyStatus {
+    fn from(err: &ServiceError) -> Self {
+        match err {
+            ServiceError::RateLimited => ReplyStatus::RateLimited,
+            ServiceError::InsufficientPow => ReplyStatus::InsufficientPow,
+            _ => ReplyStatus::HandlerError,
+        }
+    }
+}
+
+/// Network-level reply to a [`Query`]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reply {
+    /// Id of the query this reply answers
+    pub query_id: QueryId,
+    /// The service that processed the query
+    pub service_id: u16,
+    /// Type of the answered query
+    pub method_id: u16,
+    pub status: ReplyStatus,
+    /// The reply's data in bytes. Empty unless the status is `Ok`
+    pub bytes: Bytes,
+}
+
+/// Type that can be sent as a reply
+pub trait Replyable: Serialize + DeserializeOwned {}
 
 impl<T: Serialize + DeserializeOwned> Replyable for T {}
 
 impl Reply {
-    pub fn parse<T: Replyable>(&self) -> Result<T, postcard::Error> {
-        todo!()
+    /// Successful reply to `query` with encoded `bytes`
+    pub fn ok(query: &Query, bytes: Bytes) -> Self {
+        Self { query_id: query.query_id, service_id: query.service_id, method_id: query.method_id, status: ReplyStatus::Ok, bytes }
+    }
+
+    /// Reply to `query` with an error `status`
+    pub fn error(query: &Query, status: ReplyStatus) -> Self {
+        Self { query_id: query.query_id, service_id: query.service_id, method_id: query.method_id, status, bytes: Bytes::new() }
+    }
+
+    /// Decodes the reply. Fails with `ReplyError::Remote` if the peer didn't handle the query
+    pub fn parse<T: Replyable>(&self) -> Result<T, ReplyError> {
+        match self.status {
+            ReplyStatus::Ok => Ok(postcard::from_bytes(&self.bytes)?),
+            status => Err(ReplyError::Remote(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> Query {
+        Query { bytes: Bytes::new(), service_id: 1, method_id: 2, query_id: 3, stamp: None, stream: None }
+    }
+
+    #[test]
+    fn test_parse() {
+        let reply = Reply::ok(&query(), postcard::to_stdvec(&42u64).unwrap().into());
+        assert_eq!(reply.parse::<u64>().unwrap(), 42);
+        assert_eq!((reply.query_id, reply.service_id, reply.method_id), (3, 1, 2));
+
+        let reply = Reply::ok(&query(), Bytes::from_static(&[0xff; 16]));
+        assert!(matches!(reply.parse::<String>(), Err(ReplyError::Decode(_))));
+
+        let reply = Reply::error(&query(), ReplyStatus::RateLimited);
+        assert!(matches!(reply.parse::<u64>(), Err(ReplyError::Remote(ReplyStatus::RateLimited))));
     }
-}
\ No newline at end of file
+}
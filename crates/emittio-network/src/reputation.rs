@@ -0,0 +1,212 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{peer::PeerId, pow::current_time};
+
+/// Added to the score of a peer whose reply passed verification
+pub const REWARD: f64 = 1.0;
+/// Subtracted from the score of a peer whose reply failed verification. Lying must cost more than honesty earns
+pub const PENALTY: f64 = 5.0;
+/// Time it takes a score to decay halfway towards zero
+pub const HALF_LIFE: Duration = Duration::from_secs(60 * 60);
+/// A peer whose score drops to this value is banned
+pub const BAN_SCORE: f64 = -15.0;
+pub const BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Scores below this magnitude are forgotten by `prune`
+const MIN_SCORE: f64 = 0.01;
+
+struct Score {
+    value: f64,
+    updated: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Score {
+    fn decayed(&self, now: Instant) -> f64 {
+        let half_lives = now.duration_since(self.updated).as_secs_f64() / HALF_LIFE.as_secs_f64();
+        self.value * 0.5f64.powf(half_lives)
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Snapshot of a peer's reputation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerScore {
+    pub score: f64,
+    /// Time left until the ban is lifted
+    pub banned_for: Option<Duration>,
+}
+
+/// Scores kept across restarts. They keep decaying, and bans keep running, while the node is down
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedScores {
+    /// Unix time in seconds
+    pub saved_at: u64,
+    pub scores: Vec<(PeerId, PeerScore)>,
+}
+
+/// Per-peer scores driven by verification results. Scores outlive connections and decay towards zero over time,
+/// so old misbehaviour is eventually forgiven and old merit doesn't shield a peer that started lying
+#[derive(Default)]
+pub struct Reputation {
+    scores: HashMap<PeerId, Score>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a verification result, banning the peer if its score drops to `BAN_SCORE`
+    pub fn record(&mut self, peer_id: PeerId, verified: bool) {
+        let now = Instant::now();
+        let score = self.scores.entry(peer_id).or_insert(Score { value: 0.0, updated: now, banned_until: None });
+
+        score.value = score.decayed(now) + if verified { REWARD } else { -PENALTY };
+        score.updated = now;
+
+        if score.value <= BAN_SCORE && !score.is_banned(now) {
+            score.banned_until = Some(now + BAN_DURATION);
+        }
+    }
+
+    /// Current score of the peer. Unknown peers have a neutral score of zero
+    pub fn score(&self, peer_id: &PeerId) -> f64 {
+        self.scores.get(peer_id).map_or(0.0, |s| s.decayed(Instant::now()))
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.scores.get(peer_id).is_some_and(|s| s.is_banned(Instant::now()))
+    }
+
+    /// Scores of all known peers, best first
+    pub fn scores(&self) -> Vec<(PeerId, PeerScore)> {
+        let now = Instant::now();
+
+        let mut scores: Vec<_> = self.scores.iter()
+            .map(|(peer_id, s)| (*peer_id, PeerScore {
+                score: s.decayed(now),
+                banned_for: s.banned_until.and_then(|until| until.checked_duration_since(now)).filter(|d| !d.is_zero()),
+            }))
+            .collect();
+
+        scores.sort_by(|(_, a), (_, b)| b.score.total_cmp(&a.score));
+        scores
+    }
+
+    /// Scores of all known peers to be restored after a restart
+    pub fn save(&self) -> SavedScores {
+        SavedScores { saved_at: current_time(), scores: self.scores() }
+    }
+
+    /// Restores saved scores, aged by the time since they were saved. Peers scored since the start keep their scores
+    pub fn restore(&mut self, saved: SavedScores) {
+        let now = Instant::now();
+        let elapsed = Duration::from_secs(current_time().saturating_sub(saved.saved_at));
+
+        for (peer_id, saved) in saved.scores {
+            let score = Score {
+                value: saved.score * 0.5f64.powf(elapsed.as_secs_f64() / HALF_LIFE.as_secs_f64()),
+                updated: now,
+                banned_until: saved.banned_for.and_then(|banned_for| banned_for.checked_sub(elapsed)).map(|left| now + left),
+            };
+
+            self.scores.entry(peer_id).or_insert(score);
+        }
+
+        self.prune();
+    }
+
+    /// Forgets peers whose scores have decayed to nothing and whose bans have expired
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+
+        self.scores.retain(|_, s| s.is_banned(now) || s.decayed(now).abs() >= MIN_SCORE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emittio_crypto::id::Id;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_decay() {
+        let mut reputation = Reputation::new();
+        let peer = Id::default();
+
+        for _ in 0..4 {
+            reputation.record(peer, true);
+        }
+        assert_eq!(reputation.score(&peer), 4.0 * REWARD);
+
+        tokio::time::advance(HALF_LIFE).await;
+        assert!((reputation.score(&peer) - 2.0 * REWARD).abs() < 1e-9);
+
+        tokio::time::advance(HALF_LIFE * 20).await;
+        reputation.prune();
+        assert!(reputation.scores().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ban() {
+        let mut reputation = Reputation::new();
+        let liar = Id::default();
+        let honest = Id([1; 32]);
+
+        reputation.record(honest, true);
+
+        for _ in 0..(-BAN_SCORE / PENALTY) as usize {
+            assert!(!reputation.is_banned(&liar));
+            reputation.record(liar, false);
+        }
+
+        assert!(reputation.is_banned(&liar));
+        assert!(!reputation.is_banned(&honest));
+
+        let scores = reputation.scores();
+        assert_eq!(scores[0].0, honest);
+        assert_eq!(scores[1].1.banned_for, Some(BAN_DURATION));
+
+        tokio::time::advance(BAN_DURATION).await;
+        assert!(!reputation.is_banned(&liar), "ban should be temporary");
+        assert!(reputation.score(&liar) < 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restore() {
+        let mut reputation = Reputation::new();
+        let liar = Id::default();
+        let honest = Id([1; 32]);
+
+        reputation.record(honest, true);
+
+        for _ in 0..(-BAN_SCORE / PENALTY) as usize {
+            reputation.record(liar, false);
+        }
+
+        let mut saved = reputation.save();
+        saved = postcard::from_bytes(&postcard::to_stdvec(&saved).unwrap()).unwrap();
+
+        // The node was down for half of the ban
+        saved.saved_at -= BAN_DURATION.as_secs() / 2;
+
+        let mut restored = Reputation::new();
+        restored.restore(saved);
+
+        let decay = 0.5f64.powf((BAN_DURATION / 2).as_secs_f64() / HALF_LIFE.as_secs_f64());
+        assert!((restored.score(&honest) - REWARD * decay).abs() < 1e-9);
+        assert!(restored.is_banned(&liar));
+        assert_eq!(restored.scores()[1].1.banned_for, Some(BAN_DURATION / 2));
+
+        tokio::time::advance(BAN_DURATION / 2).await;
+        assert!(!restored.is_banned(&liar), "ban should end at the same time as before the restart");
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+
+use emittio_crypto::id::Id;
+use rand::{rngs::OsRng, seq::IteratorRandom};
+use tokio::time::Instant;
+
+use crate::peer::{Peer, PeerId};
+
+/// Maximum number of peers in a single bucket
+pub const K: usize = 20;
+/// Number of failed contacts after which a peer is considered dead
+pub const MAX_FAILURES: u8 = 3;
+
+const ID_BITS: usize = 256;
+
+/// XOR distance between two ids. Compares like a big-endian number
+pub fn distance(a: &Id, b: &Id) -> Id {
+    let mut out = [0u8; 32];
+
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = a.0[i] ^ b.0[i];
+    }
+
+    Id(out)
+}
+
+/// Index of the bucket `id` falls into: the length of the prefix it shares with `local`. `None` if the ids are equal
+fn bucket_index(local: &Id, id: &Id) -> Option<usize> {
+    let distance = distance(local, id);
+
+    distance.0.iter()
+        .position(|byte| *byte != 0)
+        .map(|i| i * 8 + distance.0[i].leading_zeros() as usize)
+}
+
+struct Entry {
+    peer: Peer,
+    last_seen: Option<Instant>,
+    failures: u8,
+}
+
+impl Entry {
+    fn new(peer: Peer) -> Self {
+        Self { peer, last_seen: None, failures: 0 }
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// Least recently seen peers first
+    entries: VecDeque<Entry>,
+    /// Peers that didn't fit into the full bucket. They replace dead entries
+    replacements: VecDeque<Peer>,
+}
+
+/// Kademlia-style routing table. Peers are kept in buckets by the length of the id prefix they share with the local id
+pub struct RoutingTable {
+    local: PeerId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(local: PeerId) -> Self {
+        Self { local, buckets: (0..ID_BITS).map(|_| Bucket::default()).collect() }
+    }
+
+    pub fn local_id(&self) -> &PeerId {
+        &self.local
+    }
+
+    /// Adds a peer or updates its address. A full bucket keeps its peers (long-lived peers are more likely to stay online)
+    /// and remembers the new one as a replacement. Returns whether the peer is in the table
+    pub fn insert(&mut self, peer: Peer) -> bool {
+        let Some(bucket) = self.bucket_mut(&peer.id) else {
+            return false;
+        };
+
+        if let Some(entry) = bucket.entries.iter_mut().find(|e| e.peer.id == peer.id) {
+            entry.peer = peer;
+            return true;
+        }
+
+        if bucket.entries.len() < K {
+            bucket.entries.push_back(Entry::new(peer));
+            return true;
+        }
+
+        bucket.replacements.retain(|p| p.id != peer.id);
+        bucket.replacements.push_back(peer);
+
+        if bucket.replacements.len() > K {
+            bucket.replacements.pop_front();
+        }
+
+        false
+    }
+
+    /// Marks the peer as alive, moving it to the tail of its bucket
+    pub fn seen(&mut self, peer_id: &PeerId) {
+        let Some(bucket) = self.bucket_mut(peer_id) else {
+            return;
+        };
+
+        let Some(i) = bucket.entries.iter().position(|e| e.peer.id == *peer_id) else {
+            return;
+        };
+
+        let mut entry = bucket.entries.remove(i).expect("position is in bounds");
+        entry.last_seen = Some(Instant::now());
+        entry.failures = 0;
+
+        bucket.entries.push_back(entry);
+    }
+
+    /// Records a failed contact. After `MAX_FAILURES` in a row the peer is replaced with the most recent replacement
+    pub fn failed(&mut self, peer_id: &PeerId) {
+        let Some(bucket) = self.bucket_mut(peer_id) else {
+            return;
+        };
+
+        let Some(i) = bucket.entries.iter().position(|e| e.peer.id == *peer_id) else {
+            return;
+        };
+
+        bucket.entries[i].failures += 1;
+
+        if bucket.entries[i].failures >= MAX_FAILURES {
+            bucket.entries.remove(i);
+
+            if let Some(peer) = bucket.replacements.pop_back() {
+                bucket.entries.push_back(Entry::new(peer));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<Peer> {
+        let bucket = self.bucket_mut(peer_id)?;
+        let i = bucket.entries.iter().position(|e| e.peer.id == *peer_id)?;
+
+        bucket.entries.remove(i).map(|e| e.peer)
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&Peer> {
+        self.entry(peer_id).map(|e| &e.peer)
+    }
+
+    /// When the peer was last heard from. `None` for peers that haven't answered yet
+    pub fn last_seen(&self, peer_id: &PeerId) -> Option<Instant> {
+        self.entry(peer_id)?.last_seen
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.entries.is_empty())
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.buckets.iter().flat_map(|b| b.entries.iter().map(|e| &e.peer))
+    }
+
+    /// Up to `count` peers closest to `target` by XOR distance, closest first
+    pub fn closest(&self, target: &Id, count: usize) -> Vec<PeerId> {
+        let mut ids: Vec<PeerId> = self.peers().map(|p| p.id).collect();
+
+        ids.sort_by_key(|id| distance(id, target).0);
+        ids.truncate(count);
+
+        ids
+    }
+
+    /// Up to `count` peers chosen at random
+    pub fn random(&self, count: usize) -> Vec<PeerId> {
+        self.peers().map(|p| p.id).choose_multiple(&mut OsRng, count)
+    }
+
+    /// Up to `max_count` peers satisfying `peer_id & bucket == bucket`
+    pub fn in_bucket(&self, bucket: &Id, max_count: usize) -> Vec<PeerId> {
+        self.peers()
+            .map(|p| p.id)
+            .filter(|id| *id & *bucket == *bucket)
+            .take(max_count)
+            .collect()
+    }
+
+    fn entry(&self, peer_id: &PeerId) -> Option<&Entry> {
+        let i = bucket_index(&self.local, peer_id)?;
+
+        self.buckets[i].entries.iter().find(|e| e.peer.id == *peer_id)
+    }
+
+    fn bucket_mut(&mut self, peer_id: &PeerId) -> Option<&mut Bucket> {
+        let i = bucket_index(&self.local, peer_id)?;
+
+        Some(&mut self.buckets[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emittio_crypto::{derivable::Derivable, kem::Kem};
+
+    use super::*;
+
+    fn peer(id: Id) -> Peer {
+        Peer { id, pk: Kem::random().pk, address: String::new() }
+    }
+
+    /// Id that shares exactly `prefix` bits with the zero id, with `tail` in the last byte
+    fn id_in_bucket(prefix: usize, tail: u8) -> Id {
+        let mut id = [0u8; 32];
+        id[prefix / 8] = 0x80 >> (prefix % 8);
+        id[31] |= tail;
+        Id(id)
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        let local = Id::default();
+
+        assert_eq!(bucket_index(&local, &local), None);
+        assert_eq!(bucket_index(&local, &id_in_bucket(0, 0)), Some(0));
+        assert_eq!(bucket_index(&local, &id_in_bucket(13, 1)), Some(13));
+        assert_eq!(bucket_index(&local, &id_in_bucket(255, 0)), Some(255));
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(Id::default());
+
+        for prefix in [0, 8, 100, 200] {
+            table.insert(peer(id_in_bucket(prefix, 0)));
+        }
+
+        let target = id_in_bucket(100, 1);
+        assert_eq!(table.closest(&target, 2), vec![id_in_bucket(100, 0), id_in_bucket(200, 0)]);
+    }
+
+    #[test]
+    fn test_in_bucket() {
+        let mut table = RoutingTable::new(Id::default());
+
+        let mut bucket = [0u8; 32];
+        bucket[0] = 0b1010_0000;
+        let bucket = Id(bucket);
+
+        let mut matching = [0u8; 32];
+        matching[0] = 0b1110_0000;
+        let mut other = [0u8; 32];
+        other[0] = 0b1100_0000;
+
+        table.insert(peer(Id(matching)));
+        table.insert(peer(Id(other)));
+
+        assert_eq!(table.in_bucket(&bucket, 10), vec![Id(matching)]);
+        assert!(table.in_bucket(&bucket, 0).is_empty());
+    }
+
+    #[test]
+    fn test_full_bucket() {
+        let mut table = RoutingTable::new(Id::default());
+
+        for tail in 0..K as u8 {
+            assert!(table.insert(peer(id_in_bucket(0, tail))));
+        }
+
+        let replacement = id_in_bucket(0, K as u8);
+        assert!(!table.insert(peer(replacement)), "full bucket should keep its peers");
+        assert!(table.get(&replacement).is_none());
+
+        let dead = id_in_bucket(0, 0);
+        for _ in 0..MAX_FAILURES {
+            table.failed(&dead);
+        }
+
+        assert!(table.get(&dead).is_none(), "dead peer should be removed");
+        assert!(table.get(&replacement).is_some(), "replacement should take the place of the dead peer");
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_seen_resets_failures() {
+        let mut table = RoutingTable::new(Id::default());
+        let id = id_in_bucket(3, 0);
+
+        table.insert(peer(id));
+        assert!(table.last_seen(&id).is_none());
+
+        for _ in 0..MAX_FAILURES - 1 {
+            table.failed(&id);
+        }
+        table.seen(&id);
+        table.failed(&id);
+
+        assert!(table.get(&id).is_some());
+        assert!(table.last_seen(&id).is_some());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{pin::Pin, time::Duration};
+
+use actorify::{Callback, Channel, ChannelError};
+use bytes::Bytes;
+use tokio::time::{Instant, Interval, interval_at};
+use tokio_stream::{Stream, StreamExt};
+use crate::{actor::NetworkActorHandle, error::ServiceError, peer::PeerId, pow::{self, SeenStamps}, query::{Query, Queryable}, transport::CHAN_SIZE};
+
+/// Encoded chunks of a streamed reply
+pub type ChunkStream = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+
+/// Handles queries addressed to a single `service_id`
+pub trait Service: Send + 'static {
+    /// Handles a raw query returning the encoded reply
+    fn handle(&mut self, incoming: IncomingQuery) -> impl Future<Output = Result<Bytes, ServiceError>> + Send;
+
+    /// Handles a raw streamed query returning its encoded chunks. The network actor pulls chunks only as fast
+    /// as the requester accepts them, so the stream should produce them lazily. Services don't stream by default
+    fn handle_stream(&mut self, incoming: IncomingQuery) -> impl Future<Output = Result<ChunkStream, ServiceError>> + Send {
+        async move { Err(ServiceError::UnknownMethod(incoming.query.method_id)) }
+    }
+
+    /// How often `maintain` runs. `None` disables it
+    fn maintenance_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Periodic housekeeping, e.g. dropping expired data. Runs between queries, so it doesn't need to synchronize with them
+    fn maintain(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Handles a single query type. A `Service` routes its methods to these with [`dispatch`]
+pub trait NetworkHandler<Q: Queryable> {
+    fn handle(&mut self, query: Q) -> impl Future<Output = Q::Reply> + Send;
+}
+
+/// Handles a single query type whose reply is streamed. Every chunk is a `Q::Reply` on its own,
+/// e.g. a page of a long list. A `Service` routes its methods to these with [`dispatch_stream`]
+pub trait StreamHandler<Q: Queryable> {
+    fn stream(&mut self, query: Q) -> impl Future<Output = impl Stream<Item = Q::Reply> + Send + 'static> + Send;
+}
+
+/// Query received from a remote peer
+pub struct IncomingQuery {
+    pub query: Query,
+    /// Id of the local node. Stamps of incoming queries are bound to it
+    pub local_id: PeerId,
+    /// Stamps spent on the local node
+    pub seen_stamps: SeenStamps,
+}
+
+/// Query handed over to a running service
+pub enum ServiceRequest {
+    Query {
+        incoming: IncomingQuery,
+        callback: Callback<Result<Bytes, ServiceError>>,
+    },
+    Stream {
+        incoming: IncomingQuery,
+        callback: Callback<Result<ChunkStream, ServiceError>>,
+    },
+}
+
+/// Decodes the query as `Q`, checks and spends its proof of work, passes it to the `handler` and encodes the reply.
+/// Meant to be called by `Service::handle` for each method
+pub async fn dispatch<Q, H>(handler: &mut H, incoming: &IncomingQuery) -> Result<Bytes, ServiceError>
+where
+    Q: Queryable + Send,
+    H: NetworkHandler<Q>,
+{
+    let query: Q = postcard::from_bytes(&incoming.query.bytes)?;
+
+    if !pow::accept(&incoming.query, &incoming.local_id, query.pow(), &incoming.seen_stamps) {
+        return Err(ServiceError::InsufficientPow);
+    }
+
+    let reply = handler.handle(query).await;
+
+    Ok(postcard::to_stdvec(&reply)?.into())
+}
+
+/// Like [`dispatch`], but for streamed queries. Meant to be called by `Service::handle_stream` for each streamed method
+pub async fn dispatch_stream<Q, H>(handler: &mut H, incoming: &IncomingQuery) -> Result<ChunkStream, ServiceError>
+where
+    Q: Queryable + Send,
+    H: StreamHandler<Q>,
+{
+    let query: Q = postcard::from_bytes(&incoming.query.bytes)?;
+
+    if !pow::accept(&incoming.query, &incoming.local_id, query.pow(), &incoming.seen_stamps) {
+        return Err(ServiceError::InsufficientPow);
+    }
+
+    let chunks = handler.stream(query).await
+        .filter_map(|chunk| postcard::to_stdvec(&chunk).ok().map(Bytes::from));
+
+    Ok(Box::pin(chunks))
+}
+
+/// Runs `service` in its own task. Queries and maintenance are processed one at a time, so the service can own its state
+pub fn spawn_service<S: Service>(mut service: S) -> Channel<ServiceRequest> {
+    let (tx, mut rx) = Channel::new(CHAN_SIZE);
+
+    let mut maintenance = service.maintenance_interval().map(|period| interval_at(Instant::now() + period, period));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                request = rx.recv() => match request {
+                    Some(ServiceRequest::Query { incoming, callback }) => { callback.send(service.handle(incoming).await).ok(); },
+                    Some(ServiceRequest::Stream { incoming, callback }) => { callback.send(service.handle_stream(incoming).await).ok(); },
+                    None => break,
+                },
+                _ = tick(&mut maintenance) => service.maintain().await,
+            }
+        }
+    });
+
+    tx
+}
+
+/// Waits for the next tick, forever if there is no interval
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; },
+        None => std::future::pending().await,
+    }
+}
+
+impl NetworkActorHandle {
+    /// Spawns `service` and routes incoming queries with `service_id` to it, replacing the previous service with the same id
+    pub async fn register<S: Service>(&self, service_id: u16, service: S) -> Result<(), ChannelError> {
+        self.register_service(service_id, spawn_service(service)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::{AtomicU32, Ordering}};
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{pow::PowConfig, query::PeerSelection, reply::ReplyStatus, sim::SimNetwork};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Increment(u64);
+
+    impl Queryable for Increment {
+        const SERVICE_ID: u16 = 7;
+        const METHOD_ID: u16 = 1;
+
+        type Reply = u64;
+
+        fn peer_selection(&self) -> PeerSelection {
+            PeerSelection::Random { count: 1 }
+        }
+        fn retries() -> u8 {
+            0
+        }
+        fn pow(&self) -> PowConfig {
+            PowConfig::Low
+        }
+    }
+
+    struct Counter;
+
+    impl NetworkHandler<Increment> for Counter {
+        async fn handle(&mut self, query: Increment) -> u64 {
+            query.0 + 1
+        }
+    }
+
+    impl Service for Counter {
+        async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            match incoming.query.method_id {
+                1 => dispatch::<Increment, _>(self, &incoming).await,
+                method_id => Err(ServiceError::UnknownMethod(method_id)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pow() {
+        let sim = SimNetwork::new(0);
+
+        let (node, peer) = sim.spawn_node("node");
+        node.register(7, Counter).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer.clone()).await.expect("add peer failed");
+
+        assert_eq!(Increment(41).query(&client).await.expect("query failed"), Some(42));
+
+        // The same query without a stamp is rejected
+        let query = Query { bytes: postcard::to_stdvec(&Increment(41)).unwrap().into(), service_id: 7, method_id: 1, query_id: 0, stamp: None, stream: None };
+        let replies = client.query(PeerSelection::Peers(vec![peer.clone()]), query.clone(), Duration::from_secs(5)).await
+            .expect("channel closed")
+            .expect("query failed");
+
+        assert_eq!(replies[0].1.status, ReplyStatus::InsufficientPow);
+
+        // A stamp pays for one query only
+        let mut query = query;
+        query.stamp = Some(pow::Stamp::solve(&query, &peer.id, PowConfig::Low));
+
+        for status in [ReplyStatus::Ok, ReplyStatus::InsufficientPow] {
+            let replies = client.query(PeerSelection::Peers(vec![peer.clone()]), query.clone(), Duration::from_secs(5)).await
+                .expect("channel closed")
+                .expect("query failed");
+
+            assert_eq!(replies[0].1.status, status);
+        }
+    }
+
+    /// Counts maintenance runs
+    struct Maintained(Arc<AtomicU32>);
+
+    impl Service for Maintained {
+        async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            Err(ServiceError::UnknownMethod(incoming.query.method_id))
+        }
+
+        fn maintenance_interval(&self) -> Option<Duration> {
+            Some(Duration::from_secs(60))
+        }
+
+        async fn maintain(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_maintenance() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let _service = spawn_service(Maintained(runs.clone()));
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 0, "maintenance doesn't run right away");
+
+        tokio::time::sleep(Duration::from_secs(160)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,10 +1,26 @@
-use emittio_crypto::{blake3, ciphertext::{Nonce, Sealed}, kem::SharedSecret};
+use emittio_crypto::{blake3, ciphertext::{Nonce, Sealed}, kem::{Capsule, PublicKey, SharedSecret}};
 
 use crate::{error::NetworkError, types::{Frame, FrameData}};
 
 const WINDOW: usize = 32;
 const VERSION: u8 = 1;
 
+/// 1-RTT key of a handshake. It is bound to the 0-RTT secret, so only the owner of the dialed static key can derive it
+pub fn one_rtt_key(zero_rtt: &SharedSecret, ephemeral: &SharedSecret) -> SharedSecret {
+    let mut hasher = blake3::Hasher::new_derive_key("1-rtt");
+    hasher.update(zero_rtt);
+    hasher.update(ephemeral);
+    hasher.finalize().into()
+}
+
+/// Authenticates the `pk` and `capsule` of a handshake reply under the 0-RTT secret
+pub fn handshake_auth(zero_rtt: &SharedSecret, pk: &PublicKey, capsule: &Capsule) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new_keyed(zero_rtt);
+    hasher.update(&pk.id().0);
+    hasher.update(capsule);
+    hasher.finalize()
+}
+
 pub struct Session {
     shared: SharedSecret,
     // session_id: SessionId,
@@ -54,18 +70,19 @@ impl Session {
     pub fn send(&mut self, data: &FrameData) -> Result<Frame, NetworkError> {
         self.seq += 1;
 
-        let sealed = Sealed::encrypt(&self.shared, data, self.nonce(), &self.aad())?;
+        let sealed = Sealed::encrypt(&self.shared, data, self.nonce(), &Self::aad(self.seq))?;
 
         Ok(Frame { seq: self.seq, data: sealed })
     }
 
-    pub fn recv(&mut self, frame: Frame) -> Result<FrameData, NetworkError> {
+    /// Decrypts the frame. The replay window only moves once the frame is authenticated, so a frame sealed with another key leaves the session intact
+    pub fn recv(&mut self, frame: &Frame) -> Result<FrameData, NetworkError> {
+        let data = frame.data.clone().decrypt(self.shared, &Self::aad(frame.seq))?;
+
         if !self.check_seq(frame.seq) {
             return Err(NetworkError::InvalidSeq);
         }
 
-        let data = frame.data.decrypt(self.shared, &self.aad())?;
-
         Ok(data)
     }
 
@@ -90,10 +107,10 @@ impl Session {
         nonce
     }
 
-    fn aad(&self) -> [u8; 41] {
+    fn aad(seq: u64) -> [u8; 41] {
         let mut aad = [0u8; 41];
         aad[0] = VERSION;
-        aad[1..9].copy_from_slice(&self.seq.to_be_bytes());
+        aad[1..9].copy_from_slice(&seq.to_be_bytes());
         aad
     }
 }
\ No newline at end of file
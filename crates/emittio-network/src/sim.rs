@@ -0,0 +1,260 @@
+use std::{collections::HashMap, io, sync::{Arc, Mutex}, time::Duration};
+
+use actorify::Actor;
+use emittio_crypto::{derivable::Derivable, kem::Kem};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::sync::mpsc;
+
+use crate::{actor::{NetworkActor, NetworkActorHandle}, error::NetworkError, peer::Peer, transport::{CHAN_SIZE, Connection, Transport}, types::Packet};
+
+/// Conditions applied to every packet travelling through the simulated network
+#[derive(Clone, Default)]
+pub struct LinkConfig {
+    /// Base delay of each packet
+    pub latency: Duration,
+    /// Random extra delay in range `[0, jitter]`. Packets are delayed independently, so jitter reorders them
+    pub jitter: Duration,
+    /// Probability of a packet being dropped, in range [0.0, 1.0]
+    pub loss: f64,
+}
+
+struct SimState {
+    listeners: HashMap<String, mpsc::Sender<Connection>>,
+    /// Nodes only reach nodes of the same group. Nodes without a group are in group 0
+    groups: HashMap<String, u32>,
+    link: LinkConfig,
+    rng: StdRng,
+}
+
+impl SimState {
+    fn reachable(&self, from: &str, to: &str) -> bool {
+        self.groups.get(from).unwrap_or(&0) == self.groups.get(to).unwrap_or(&0)
+    }
+}
+
+/// In-memory network connecting many `NetworkActor`s inside a single runtime.
+///
+/// Randomness is taken from a seeded generator, so a test with paused time replays the same way every run
+#[derive(Clone)]
+pub struct SimNetwork(Arc<Mutex<SimState>>);
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(SimState {
+            listeners: HashMap::new(),
+            groups: HashMap::new(),
+            link: LinkConfig::default(),
+            rng: StdRng::seed_from_u64(seed),
+        })))
+    }
+
+    /// Transport that dials from `address`
+    pub fn transport(&self, address: &str) -> SimTransport {
+        SimTransport { network: self.clone(), address: address.to_string() }
+    }
+
+    /// Accepts connections dialed to `address`
+    pub fn listen(&self, address: &str) -> mpsc::Receiver<Connection> {
+        let (tx, rx) = mpsc::channel(CHAN_SIZE);
+        self.state().listeners.insert(address.to_string(), tx);
+        rx
+    }
+
+    /// Spawns a node with a random static identity listening on `address`
+    pub fn spawn_node(&self, address: &str) -> (NetworkActorHandle, Peer) {
+        let identity = Kem::random();
+        let peer = Peer { id: identity.pk.id(), pk: identity.pk.clone(), address: address.to_string() };
+
+        (self.spawn_actor(address, Some(identity)), peer)
+    }
+
+    /// Spawns a client that can only initiate connections
+    pub fn spawn_client(&self, address: &str) -> NetworkActorHandle {
+        self.spawn_actor(address, None)
+    }
+
+    fn spawn_actor(&self, address: &str, identity: Option<Kem>) -> NetworkActorHandle {
+        let network = NetworkActor::new(self.transport(address), identity).spawn();
+        let mut listener = self.listen(address);
+
+        let handle = network.clone();
+        tokio::spawn(async move {
+            while let Some(conn) = listener.recv().await {
+                if handle.accept(conn).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        network
+    }
+
+    /// Replaces conditions of all links. Affects packets sent after the call
+    pub fn set_link(&self, link: LinkConfig) {
+        self.state().link = link;
+    }
+
+    /// Moves `addresses` into a separate `group`. Packets and new connections between different groups are dropped
+    pub fn partition(&self, addresses: &[&str], group: u32) {
+        let mut state = self.state();
+
+        for address in addresses {
+            state.groups.insert(address.to_string(), group);
+        }
+    }
+
+    /// Removes all partitions
+    pub fn heal(&self) {
+        self.state().groups.clear();
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, SimState> {
+        self.0.lock().expect("sim network state poisoned")
+    }
+
+    /// Moves packets from `outgoing` to `incoming` applying link conditions
+    async fn pump(self, from: String, to: String, mut outgoing: mpsc::Receiver<Packet>, incoming: mpsc::Sender<Packet>) {
+        while let Some(packet) = outgoing.recv().await {
+            let delay = {
+                let mut state = self.state();
+
+                if !state.reachable(&from, &to) {
+                    continue;
+                }
+
+                let link = state.link.clone();
+
+                if link.loss > 0.0 && state.rng.gen_bool(link.loss.min(1.0)) {
+                    continue;
+                }
+
+                link.latency + state.rng.gen_range(Duration::ZERO..=link.jitter)
+            };
+
+            if delay.is_zero() {
+                if incoming.send(packet).await.is_err() {
+                    break;
+                }
+            } else {
+                let incoming = incoming.clone();
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    incoming.send(packet).await.ok();
+                });
+            }
+        }
+    }
+}
+
+/// Dials other nodes of a [`SimNetwork`]
+pub struct SimTransport {
+    network: SimNetwork,
+    address: String,
+}
+
+impl Transport for SimTransport {
+    async fn connect(&mut self, address: &str) -> Result<Connection, NetworkError> {
+        let listener = {
+            let state = self.network.state();
+
+            match state.listeners.get(address) {
+                Some(listener) if state.reachable(&self.address, address) => listener.clone(),
+                _ => return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into()),
+            }
+        };
+
+        let (local, local_out, local_in) = Connection::new();
+        let (remote, remote_out, remote_in) = Connection::new();
+
+        tokio::spawn(self.network.clone().pump(self.address.clone(), address.to_string(), local_out, remote_in));
+        tokio::spawn(self.network.clone().pump(address.to_string(), self.address.clone(), remote_out, local_in));
+
+        listener.send(remote).await.map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        Ok(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use emittio_crypto::id::Id;
+    use tokio::time::{Instant, timeout};
+
+    use crate::types::{Chunk, Frame, FrameData};
+    use crate::session::Session;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn packet(seq: u64) -> Packet {
+        let mut session = Session::new([seq as u8; 32], true);
+        let Frame { data, .. } = session.send(&FrameData::Chunk(Chunk::Data { query_id: 0, bytes: Id::default().0.to_vec().into() })).unwrap();
+        Packet::Frame(Frame { seq, data })
+    }
+
+    fn seq(packet: Packet) -> u64 {
+        match packet {
+            Packet::Frame(frame) => frame.seq,
+            Packet::Handshake(_) => unreachable!(),
+        }
+    }
+
+    async fn connect(sim: &SimNetwork) -> (Connection, Connection) {
+        let mut listener = sim.listen("b");
+        let a = sim.transport("a").connect("b").await.expect("connect failed");
+        let b = listener.recv().await.expect("listener closed");
+        (a, b)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency() {
+        let sim = SimNetwork::new(0);
+        sim.set_link(LinkConfig { latency: Duration::from_millis(200), ..Default::default() });
+
+        let (a, mut b) = connect(&sim).await;
+
+        let start = Instant::now();
+        a.tx.send(packet(1)).await.unwrap();
+
+        assert_eq!(seq(b.rx.recv().await.unwrap()), 1);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loss_and_reordering() {
+        let sim = SimNetwork::new(7);
+        sim.set_link(LinkConfig { latency: Duration::from_millis(10), jitter: Duration::from_millis(100), loss: 0.5 });
+
+        let (a, mut b) = connect(&sim).await;
+
+        for i in 1..=100 {
+            a.tx.send(packet(i)).await.unwrap();
+        }
+        drop(a);
+
+        let mut received = Vec::new();
+        while let Ok(Some(packet)) = timeout(TIMEOUT, b.rx.recv()).await {
+            received.push(seq(packet));
+        }
+
+        assert!(!received.is_empty() && received.len() < 100, "some packets should be lost");
+        assert!(received.windows(2).any(|w| w[0] > w[1]), "packets should be reordered");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partition() {
+        let sim = SimNetwork::new(0);
+        let (a, mut b) = connect(&sim).await;
+
+        sim.partition(&["b"], 1);
+        a.tx.send(packet(1)).await.unwrap();
+        assert!(timeout(TIMEOUT, b.rx.recv()).await.is_err(), "packet should not cross the partition");
+        assert!(sim.transport("a").connect("b").await.is_err(), "connection should not cross the partition");
+
+        sim.heal();
+        a.tx.send(packet(2)).await.unwrap();
+        assert_eq!(seq(b.rx.recv().await.unwrap()), 2);
+    }
+}
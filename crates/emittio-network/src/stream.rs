@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio_stream::Stream;
+
+use crate::{actor::NetworkActorHandle, error::NetworkError, peer::Peer, query::Query};
+
+/// Default number of chunks a responder may send ahead of the ones the requester has consumed
+pub const STREAM_WINDOW: u32 = 16;
+/// Largest window a responder grants. Bigger windows requested by peers are reduced to it
+pub const MAX_STREAM_WINDOW: u32 = 256;
+/// A stream is closed once its peer has sent no chunk, or no credit, for this long
+pub const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl NetworkActorHandle {
+    /// Sends a streamed query to `peer` and returns its reply chunks as they arrive.
+    ///
+    /// The peer may send up to `window` chunks ahead. Credit for more is granted as chunks are consumed,
+    /// in batches of half the window, so a slow consumer slows the responder down instead of piling chunks up in memory.
+    /// The stream ends after the last chunk, or with an error if the peer fails, disconnects or stays silent for `STREAM_IDLE_TIMEOUT`
+    pub async fn query_stream(&self, peer: Peer, query: Query, window: u32) -> Result<impl Stream<Item = Result<Bytes, NetworkError>> + use<>, NetworkError> {
+        let (query_id, chunks) = self.open_stream(peer, query, window).await??;
+
+        let network = self.clone();
+        let batch = (window / 2).max(1);
+
+        Ok(futures::stream::unfold((chunks, 0), move |(mut chunks, mut consumed)| {
+            let network = network.clone();
+
+            async move {
+                let chunk = chunks.recv().await?;
+
+                consumed += 1;
+                if consumed == batch {
+                    network.grant_credit(query_id, consumed).await.ok();
+                    consumed = 0;
+                }
+
+                Some((chunk, (chunks, consumed)))
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{Arc, atomic::{AtomicU32, Ordering}}, time::Duration};
+
+    use serde::{Deserialize, Serialize};
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+
+    use crate::{error::{ReplyError, ServiceError}, query::{PeerSelection, Queryable}, reply::ReplyStatus, service::{ChunkStream, IncomingQuery, Service, StreamHandler, dispatch_stream}, sim::SimNetwork};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const WINDOW: u32 = 4;
+
+    /// Streams numbers from zero up to the given one
+    #[derive(Serialize, Deserialize)]
+    struct Count(u32);
+
+    impl Queryable for Count {
+        const SERVICE_ID: u16 = 7;
+        const METHOD_ID: u16 = 1;
+
+        type Reply = u32;
+
+        fn peer_selection(&self) -> PeerSelection {
+            PeerSelection::Random { count: 1 }
+        }
+        fn stream_window() -> u32 {
+            WINDOW
+        }
+    }
+
+    /// Counts the numbers it has produced
+    struct Counter(Arc<AtomicU32>);
+
+    impl StreamHandler<Count> for Counter {
+        async fn stream(&mut self, query: Count) -> impl Stream<Item = u32> + Send + 'static {
+            let produced = self.0.clone();
+
+            tokio_stream::iter((0..query.0).inspect(move |_| { produced.fetch_add(1, Ordering::SeqCst); }))
+        }
+    }
+
+    impl Service for Counter {
+        async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            Err(ServiceError::UnknownMethod(incoming.query.method_id))
+        }
+
+        async fn handle_stream(&mut self, incoming: IncomingQuery) -> Result<ChunkStream, ServiceError> {
+            match incoming.query.method_id {
+                Count::METHOD_ID => dispatch_stream::<Count, _>(self, &incoming).await,
+                method_id => Err(ServiceError::UnknownMethod(method_id)),
+            }
+        }
+    }
+
+    /// Sends the first number and then goes silent
+    struct Stalled;
+
+    impl StreamHandler<Count> for Stalled {
+        async fn stream(&mut self, _query: Count) -> impl Stream<Item = u32> + Send + 'static {
+            tokio_stream::once(0).chain(tokio_stream::pending())
+        }
+    }
+
+    impl Service for Stalled {
+        async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+            Err(ServiceError::UnknownMethod(incoming.query.method_id))
+        }
+
+        async fn handle_stream(&mut self, incoming: IncomingQuery) -> Result<ChunkStream, ServiceError> {
+            dispatch_stream::<Count, _>(self, &incoming).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_stream() {
+        let sim = SimNetwork::new(0);
+        let produced = Arc::new(AtomicU32::new(0));
+
+        let (node, peer) = sim.spawn_node("node");
+        node.register(7, Counter(produced.clone())).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+
+        let chunks = Count(100).query_stream(&client, peer).await.expect("query failed");
+        tokio::pin!(chunks);
+
+        assert_eq!(timeout(TIMEOUT, chunks.next()).await.expect("timeout").expect("stream ended").expect("chunk failed"), 0);
+
+        // Without more credit the node stops after the first window
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(produced.load(Ordering::SeqCst), WINDOW);
+
+        let rest: Vec<u32> = timeout(TIMEOUT, chunks.map(|chunk| chunk.expect("chunk failed")).collect()).await.expect("timeout");
+        assert_eq!(rest, (1..100).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_query_stream_error() {
+        let sim = SimNetwork::new(0);
+
+        let (_node, peer) = sim.spawn_node("node");
+        let client = sim.spawn_client("client");
+
+        let chunks = Count(10).query_stream(&client, peer).await.expect("query failed");
+        let chunks: Vec<_> = timeout(TIMEOUT, chunks.collect()).await.expect("timeout");
+
+        assert!(matches!(chunks[..], [Err(NetworkError::Reply(ReplyError::Remote(ReplyStatus::UnknownService)))]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_stream_idle() {
+        let sim = SimNetwork::new(0);
+
+        let (node, peer) = sim.spawn_node("node");
+        node.register(7, Stalled).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+
+        let chunks = Count(10).query_stream(&client, peer).await.expect("query failed");
+        let chunks: Vec<_> = timeout(STREAM_IDLE_TIMEOUT * 2, chunks.collect()).await.expect("stream was not closed");
+
+        assert!(matches!(chunks[..], [Ok(0), Err(NetworkError::StreamTimeout)]));
+    }
+}
@@ -0,0 +1,153 @@
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+use crate::{actor::NetworkActorHandle, error::NetworkError, transport::{Connection, Transport}, types::Packet};
+
+/// Upper bound of a single encoded packet. A bigger length prefix is treated as a protocol violation
+pub const MAX_PACKET_LEN: usize = 1024 * 1024;
+
+/// Dials peers over TCP. `Peer::address` is a `host:port` pair
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    async fn connect(&mut self, address: &str) -> Result<Connection, NetworkError> {
+        connect(address).await
+    }
+}
+
+/// Dials `address` and wraps the stream into a packet connection
+pub async fn connect(address: &str) -> Result<Connection, NetworkError> {
+    let stream = TcpStream::connect(address).await?;
+
+    Ok(spawn_connection(stream))
+}
+
+/// Accepts incoming TCP connections and hands them over to the network actor
+pub async fn serve(listener: TcpListener, network: NetworkActorHandle) -> Result<(), NetworkError> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        network.accept(spawn_connection(stream)).await?;
+    }
+}
+
+/// Spawns reader and writer tasks that move packets between the stream and a [`Connection`]
+pub fn spawn_connection(stream: TcpStream) -> Connection {
+    stream.set_nodelay(true).ok();
+
+    let (mut reader, mut writer) = stream.into_split();
+    let (conn, mut outgoing, incoming) = Connection::new();
+
+    tokio::spawn(async move {
+        while let Some(packet) = outgoing.recv().await {
+            if write_packet(&mut writer, &packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Ok(packet) = read_packet(&mut reader).await {
+            if incoming.send(packet).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    conn
+}
+
+/// Writes a packet as a big-endian `u32` length followed by its postcard encoding
+pub async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &Packet) -> Result<(), NetworkError> {
+    let bytes = postcard::to_stdvec(packet)?;
+
+    if bytes.len() > MAX_PACKET_LEN {
+        return Err(NetworkError::PacketTooLarge(bytes.len()));
+    }
+
+    writer.write_u32(bytes.len() as u32).await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Reads a single length-prefixed packet
+pub async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet, NetworkError> {
+    let len = reader.read_u32().await? as usize;
+
+    if len > MAX_PACKET_LEN {
+        return Err(NetworkError::PacketTooLarge(len));
+    }
+
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+
+    Ok(postcard::from_bytes(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use emittio_crypto::{derivable::Derivable, kem::Kem};
+    use tokio::time::timeout;
+
+    use crate::{session::Session, types::{Chunk, FrameData, Handshake}};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn sample_packets() -> Vec<Packet> {
+        let alice = Kem::random();
+        let bob = Kem::random();
+
+        let (capsule, shared) = alice.sk.shared(&bob.pk).expect("shared failed");
+        let frame = Session::new(shared, true).send(&FrameData::Chunk(Chunk::Data { query_id: 0, bytes: vec![7u8; 4096].into() })).expect("send failed");
+
+        vec![
+            Packet::Handshake(Handshake { pk: alice.pk, capsule, auth: None }),
+            Packet::Frame(frame),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind failed");
+        let address = listener.local_addr().expect("no local address").to_string();
+
+        let accept = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("accept failed");
+            spawn_connection(stream)
+        });
+
+        let mut alice = connect(&address).await.expect("connect failed");
+        let mut bob = accept.await.expect("accept task failed");
+
+        let packets = sample_packets();
+
+        for packet in packets.iter() {
+            let expected = postcard::to_stdvec(packet).unwrap();
+
+            alice.tx.send(postcard::from_bytes(&expected).unwrap()).await.expect("alice send failed");
+            let received = timeout(TIMEOUT, bob.rx.recv()).await.expect("timeout").expect("connection closed");
+            assert_eq!(postcard::to_stdvec(&received).unwrap(), expected);
+
+            bob.tx.send(received).await.expect("bob send failed");
+            let echoed = timeout(TIMEOUT, alice.rx.recv()).await.expect("timeout").expect("connection closed");
+            assert_eq!(postcard::to_stdvec(&echoed).unwrap(), expected);
+        }
+
+        drop(bob);
+        assert!(timeout(TIMEOUT, alice.rx.recv()).await.expect("timeout").is_none(), "connection should be closed");
+    }
+
+    #[tokio::test]
+    async fn test_packet_too_large() {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+
+        writer.write_u32(MAX_PACKET_LEN as u32 + 1).await.unwrap();
+
+        assert!(matches!(read_packet(&mut reader).await, Err(NetworkError::PacketTooLarge(_))));
+    }
+}
@@ -0,0 +1,31 @@
+use actorify::{Channel, tokio::sync::mpsc};
+
+use crate::{error::NetworkError, types::Packet};
+
+pub(crate) const CHAN_SIZE: usize = 128;
+
+/// Packet-level connection to a single remote endpoint, independent of the underlying transport
+pub struct Connection {
+    /// Packets sent here are delivered to the remote side
+    pub tx: Channel<Packet>,
+    /// Packets received from the remote side. Closes when the connection is lost
+    pub rx: mpsc::Receiver<Packet>,
+}
+
+impl Connection {
+    /// Creates a connection and the opposite ends of its channels: a receiver of outgoing packets and a sender of incoming ones
+    pub fn new() -> (Self, mpsc::Receiver<Packet>, mpsc::Sender<Packet>) {
+        let (tx, outgoing) = Channel::new(CHAN_SIZE);
+        let (incoming, rx) = mpsc::channel(CHAN_SIZE);
+
+        (Self { tx, rx }, outgoing, incoming)
+    }
+}
+
+/// Opens connections to peer addresses. `NetworkActor` is generic over it, so the same actor runs over TCP or an in-memory network.
+///
+/// Inbound connections are not part of the trait: a listener hands them to the actor with `NetworkActorHandle::accept`
+pub trait Transport: Send + 'static {
+    /// Connects to `address` (the format is defined by the transport, see `Peer::address`)
+    fn connect(&mut self, address: &str) -> impl Future<Output = Result<Connection, NetworkError>> + Send;
+}
@@ -1,24 +1,48 @@
 use bytes::Bytes;
-use emittio_crypto::{ciphertext::Sealed, id::Id, kem::{Capsule, PublicKey}};
+use emittio_crypto::{ciphertext::Sealed, kem::{Capsule, PublicKey}};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
-use crate::query::Query;
+use crate::{query::{Query, QueryId}, reply::{Reply, ReplyStatus}};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Handshake {
     pub pk: PublicKey,
     #[serde(with = "BigArray")]
     pub capsule: Capsule,
+    /// Set by the responder only: proves it derived the 0-RTT secret, i.e. holds the static key the initiator dialed
+    pub auth: Option<[u8; 32]>,
 }
 
 pub type PayloadId = u64;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum FrameData {
     Query(Query),
-    Reply(Bytes),
-    Chunk(Bytes),
+    Reply(Reply),
+    Chunk(Chunk),
+}
+
+/// Part of a streamed reply, see [`NetworkActorHandle::query_stream`](crate::actor::NetworkActorHandle::query_stream).
+/// Streams are flow controlled with credits: the responder only sends as many `Data` chunks as the requester allowed
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Chunk {
+    /// Next piece of the reply
+    Data { query_id: QueryId, bytes: Bytes },
+    /// The stream is complete. Any status other than `Ok` means it was cut short
+    End { query_id: QueryId, status: ReplyStatus },
+    /// Allows the responder to send `credit` more `Data` chunks
+    Credit { query_id: QueryId, credit: u32 },
+    /// The requester is no longer interested in the stream
+    Cancel { query_id: QueryId },
+}
+
+impl Chunk {
+    pub fn query_id(&self) -> QueryId {
+        match self {
+            Chunk::Data { query_id, .. } | Chunk::End { query_id, .. } | Chunk::Credit { query_id, .. } | Chunk::Cancel { query_id } => *query_id,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,53 +57,6 @@ pub enum Packet {
     Frame(Frame),
 }
 
-pub struct RouteConfig {
-    pub peers: PeerSelection,
-    pub pow: PowConfig,
-}
-
-#[derive(Clone)]
-pub enum PeerSelection {
-    Closest {
-        target: Id,
-        count: u16,
-    },
-    Random(u8),
-    InBucket {
-        bucket: Id,
-        max_count: u64,
-    },
-}
-
-pub enum PowConfig {
-    None,
-    Low,
-    Medium,
-    High,
-}
-
-pub enum VerificationMethod {
-    None,
-    Median { tolerance: f32 },
-    Hash(Id),
-}
-
-pub trait IntoQuery {
-    const SERVICE_ID: u16;
-    const METHOD_ID: u16;
-
-    type Reply;
-
-    fn route_config(&self) -> RouteConfig;
-    fn verification_method(&self) -> VerificationMethod {
-        VerificationMethod::None
-    }
-}
-
-pub trait NetworkHandler<Q: IntoQuery> {
-    fn handle(&mut self, query: Q) -> impl Future<Output = Q::Reply>;
-}
-
 // pub fn median<T>(values: &mut [T]) -> T {
 //     values.sort();
 //     values[values.len() / 2]
@@ -0,0 +1,176 @@
+//! On-disk layout of `PointerStorage`:
+//!
+//! ```text
+//! dir/
+//!   pointers.log           pointers stored since the last checkpoint
+//!   blocks/{time}/mask     bucket mask of the block
+//!   blocks/{time}/{bucket} pointers of a bucket as of the last checkpoint
+//! ```
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use emittio_crypto::id::{Id, Mask};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::{self, File, OpenOptions}, io::AsyncWriteExt};
+
+use crate::{error::StorageError, types::{BlockTime, Pointer}};
+
+const LOG_FILE: &str = "pointers.log";
+const BLOCKS_DIR: &str = "blocks";
+const MASK_FILE: &str = "mask";
+
+/// Appended to the log for every stored pointer. `index` is the position of the pointer in its bucket,
+/// so records that already made it into a bucket file are skipped on replay
+#[derive(Serialize, Deserialize)]
+pub struct LogRecord {
+    pub time: BlockTime,
+    pub bucket: Id,
+    pub index: u64,
+    pub pointer: Pointer,
+}
+
+/// Block as of the last checkpoint
+pub struct StoredBlock {
+    pub time: BlockTime,
+    pub mask: Mask,
+    pub buckets: HashMap<Id, Vec<Pointer>>,
+}
+
+pub fn block_dir(dir: &Path, time: BlockTime) -> PathBuf {
+    dir.join(BLOCKS_DIR).join(time.to_string())
+}
+
+pub fn bucket_path(dir: &Path, time: BlockTime, bucket: &Id) -> PathBuf {
+    block_dir(dir, time).join(bucket.to_string())
+}
+
+pub fn mask_path(dir: &Path, time: BlockTime) -> PathBuf {
+    block_dir(dir, time).join(MASK_FILE)
+}
+
+/// Writes `value` through a temporary file, so a crash never leaves a partially written file behind
+pub async fn write_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), StorageError> {
+    let tmp = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp).await?;
+    file.write_all(&postcard::to_stdvec(value)?).await?;
+    file.sync_all().await?;
+
+    fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+/// Reads all blocks written by checkpoints. Leftover temporary files are ignored
+pub async fn read_blocks(dir: &Path) -> Result<Vec<StoredBlock>, StorageError> {
+    let blocks_dir = dir.join(BLOCKS_DIR);
+    fs::create_dir_all(&blocks_dir).await?;
+
+    let mut blocks = Vec::new();
+    let mut entries = fs::read_dir(&blocks_dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(time) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+
+        // The mask is written when the block is created, a block without it never stored anything
+        let Ok(mask) = fs::read(mask_path(dir, time)).await else {
+            continue;
+        };
+
+        let mut buckets = HashMap::new();
+        let mut files = fs::read_dir(entry.path()).await?;
+
+        while let Some(file) = files.next_entry().await? {
+            let Some(bucket) = file.file_name().to_str().and_then(parse_id) else {
+                continue;
+            };
+
+            buckets.insert(bucket, postcard::from_bytes(&fs::read(file.path()).await?)?);
+        }
+
+        blocks.push(StoredBlock { time, mask: postcard::from_bytes(&mask)?, buckets });
+    }
+
+    Ok(blocks)
+}
+
+fn parse_id(hex: &str) -> Option<Id> {
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut id = [0u8; 32];
+
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(Id(id))
+}
+
+/// Append-only log of stored pointers. Records are length-prefixed and synced one by one
+pub struct Log {
+    file: File,
+    len: usize,
+}
+
+impl Log {
+    /// Opens the log returning its records. A record torn by a crash ends the log and is cut off
+    pub async fn open(dir: &Path) -> Result<(Self, Vec<LogRecord>), StorageError> {
+        let path = dir.join(LOG_FILE);
+        let bytes = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut records = Vec::new();
+        let mut valid = 0;
+
+        while let Some(len) = bytes.get(valid..valid + 4).map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            && let Some(record) = bytes.get(valid + 4..valid + 4 + len)
+            && let Ok(record) = postcard::from_bytes(record)
+        {
+            records.push(record);
+            valid += 4 + len;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        file.set_len(valid as u64).await?;
+
+        Ok((Self { file, len: records.len() }, records))
+    }
+
+    /// Appends the records, syncing them to disk together
+    pub async fn append(&mut self, records: &[LogRecord]) -> Result<(), StorageError> {
+        let mut framed = Vec::new();
+
+        for record in records {
+            let bytes = postcard::to_stdvec(record)?;
+
+            framed.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            framed.extend_from_slice(&bytes);
+        }
+
+        self.file.write_all(&framed).await?;
+        self.file.sync_data().await?;
+        self.len += records.len();
+
+        Ok(())
+    }
+
+    /// Number of records in the log
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub async fn clear(&mut self) -> Result<(), StorageError> {
+        self.file.set_len(0).await?;
+        self.file.sync_data().await?;
+        self.len = 0;
+
+        Ok(())
+    }
+}
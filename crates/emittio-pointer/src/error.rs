@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Why a node can't answer for a block
+#[derive(Debug, Error, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block is older than the node's retention window, its pointers were dropped
+    #[error("block has expired")]
+    Expired,
+}
+
+/// Why a pointer wasn't stored
+#[derive(Debug, Error, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PutPointerError {
+    /// The bucket holds `MAX_POINTERS_IN_BLOCK` pointers already. The next block gets a finer mask
+    #[error("bucket is full")]
+    BucketFull,
+
+    /// The bucket holds the same pointer already
+    #[error("duplicate pointer")]
+    Duplicate,
+
+    /// A point of the tag is malformed or of small order
+    #[error("invalid tag")]
+    InvalidTag,
+
+    /// The node requires pointed content to be stored locally and doesn't have it
+    #[error("unknown cid")]
+    UnknownCid,
+
+    #[error("internal error")]
+    Internal,
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Postcard(#[from] postcard::Error),
+}
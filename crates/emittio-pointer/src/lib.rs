@@ -1,7 +1,18 @@
+pub mod error;
 pub mod query;
 pub mod types;
 #[cfg(feature = "node")]
+mod disk;
+#[cfg(feature = "node")]
 pub mod service;
+#[cfg(feature = "node")]
+mod sync;
 pub mod utils;
 
-pub const POINTER_SERVICE_ID: u16 = 1;
\ No newline at end of file
+use emittio_network::queries;
+
+use crate::query::{CountPointers, GetDigests, GetMask, GetPointers, PutPointer};
+
+pub const POINTER_SERVICE_ID: u16 = 1;
+
+queries!(pub QUERIES = [CountPointers, GetPointers, PutPointer, GetMask, GetDigests]);
\ No newline at end of file
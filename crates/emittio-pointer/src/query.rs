@@ -1,31 +1,55 @@
-use emittio_crypto::id::Id;
-use emittio_network::types::{PeerSelection, PowConfig, IntoQuery, RouteConfig, VerificationMethod};
+use emittio_crypto::id::{Id, Mask};
+use emittio_network::{pow::PowConfig, query::{PeerSelection, Queryable}, verifier::{MedianVerifier, VerificationInput, VerificationOutput, Verifier}};
 use serde::{Deserialize, Serialize};
 
-use crate::{POINTER_SERVICE_ID, types::{BlockTime, MAX_POINTERS_IN_BLOCK, Pointer}};
+use crate::{POINTER_SERVICE_ID, error::{BlockError, PutPointerError}, types::{BlockDigest, BlockTime, MAX_POINTERS_IN_BLOCK, Pointer}};
 
-const MEDIAN_TOLERANCE: f32 = 0.05;
+const MEDIAN_TOLERANCE: f64 = 0.05;
+/// How many replicas a node compares its pointers with in every synchronization round
+pub const SYNC_PEERS: u8 = 3;
+/// How many nodes of a bucket a pointer is put on. Every one costs the sender a stamp, replicas spread it to the rest of the bucket
+pub const REPLICATION: u64 = 5;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct CountPointers {
     pub time: BlockTime,
 }
 
-impl IntoQuery for CountPointers {
+impl Queryable for CountPointers {
     const SERVICE_ID: u16 = POINTER_SERVICE_ID;
     const METHOD_ID: u16 = 1;
 
-    type Reply = u64;
+    type Reply = Result<u64, BlockError>;
 
-    fn route_config(&self) -> RouteConfig {
-        RouteConfig { peers: PeerSelection::Random(5), pow: PowConfig::None }
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Random { count: 5 }
     }
 
-    fn verification_method(&self) -> VerificationMethod {
-        VerificationMethod::Median { tolerance: MEDIAN_TOLERANCE }
+    fn verifier(&self) -> impl Verifier<Self::Reply> {
+        CountPointersVerifier(MedianVerifier { tolerance: MEDIAN_TOLERANCE })
     }
 }
 
+/// Takes the median of the counts. Errors are neither verified nor rejected, and are only returned if no peer counted the block
+pub struct CountPointersVerifier(pub MedianVerifier);
+
+impl Verifier<Result<u64, BlockError>> for CountPointersVerifier {
+    fn verify(&self, replies: VerificationInput<Result<u64, BlockError>>) -> VerificationOutput<Result<u64, BlockError>> {
+        let mut error = None;
+
+        let counts = replies.into_iter()
+            .filter_map(|(id, reply)| reply.map_err(|err| error = Some(err)).ok().map(|count| (id, count)))
+            .collect();
+
+        let (results, reply) = self.0.verify(counts);
+
+        (results, reply.map(Ok).or(error.map(Err)))
+    }
+}
+
+/// Pointers of a bucket starting at `cursor`. Streamed with `query_stream` it returns the whole range in pages,
+/// so a bucket can be scanned with `count: u64::MAX` in a single request.
+/// Nodes normalize `bucket` with the block's mask, but peers are selected by it as is, so it should be normalized with [`GetMask`] first
 #[derive(Clone, Serialize, Deserialize)]
 pub struct GetPointers {
     pub time: BlockTime,
@@ -34,14 +58,81 @@ pub struct GetPointers {
     pub count: u64,
 }
 
-impl IntoQuery for GetPointers {
+impl Queryable for GetPointers {
     const SERVICE_ID: u16 = POINTER_SERVICE_ID;
     const METHOD_ID: u16 = 2;
 
-    type Reply = Vec<Pointer>;
+    type Reply = Result<Vec<Pointer>, BlockError>;
 
-    fn route_config(&self) -> RouteConfig {
-        RouteConfig { peers: PeerSelection::InBucket { bucket: self.bucket.clone(), max_count: MAX_POINTERS_IN_BLOCK }, pow: PowConfig::None }
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::InBucket { bucket: self.bucket, max_count: MAX_POINTERS_IN_BLOCK }
+    }
+}
+
+/// Mask a node uses to normalize buckets of a block. It gets finer as more pointers are stored per block,
+/// so that buckets hold no more than `MAX_POINTERS_IN_BLOCK` pointers
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetMask {
+    pub time: BlockTime,
+}
+
+impl Queryable for GetMask {
+    const SERVICE_ID: u16 = POINTER_SERVICE_ID;
+    const METHOD_ID: u16 = 4;
+
+    type Reply = Result<Mask, BlockError>;
+
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Random { count: 5 }
+    }
+
+    fn verifier(&self) -> impl Verifier<Self::Reply> {
+        MaskVerifier
+    }
+}
+
+/// Takes the mask most peers agree on. Nodes derive masks from their own pointer counts, so a different mask isn't a lie
+/// and peers that disagree are neither verified nor rejected. Errors are only returned if no peer sent a mask
+pub struct MaskVerifier;
+
+impl Verifier<Result<Mask, BlockError>> for MaskVerifier {
+    fn verify(&self, replies: VerificationInput<Result<Mask, BlockError>>) -> VerificationOutput<Result<Mask, BlockError>> {
+        let mut error = None;
+        let mut votes: Vec<(Mask, Vec<Id>)> = Vec::new();
+
+        for (id, reply) in replies {
+            match reply {
+                Ok(mask) => match votes.iter_mut().find(|(m, _)| *m == mask) {
+                    Some((_, ids)) => ids.push(id),
+                    None => votes.push((mask, vec![id])),
+                },
+                Err(err) => error = Some(err),
+            }
+        }
+
+        match votes.into_iter().max_by_key(|(_, ids)| ids.len()) {
+            Some((mask, ids)) => (ids.into_iter().map(|id| (id, true)).collect(), Some(Ok(mask))),
+            None => (Vec::new(), error.map(Err)),
+        }
+    }
+}
+
+/// Digests of every block from `from` to `to` inclusive, one block per chunk. Replicas compare them to find buckets they are missing pointers of.
+/// Only answered as a stream
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetDigests {
+    pub from: BlockTime,
+    pub to: BlockTime,
+}
+
+impl Queryable for GetDigests {
+    const SERVICE_ID: u16 = POINTER_SERVICE_ID;
+    const METHOD_ID: u16 = 5;
+
+    type Reply = BlockDigest;
+
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::Random { count: SYNC_PEERS }
     }
 }
 
@@ -51,13 +142,45 @@ pub struct PutPointer {
     pub pointer: Pointer,
 }
 
-impl IntoQuery for PutPointer {
+impl Queryable for PutPointer {
     const SERVICE_ID: u16 = POINTER_SERVICE_ID;
     const METHOD_ID: u16 = 3;
 
-    type Reply = ();
+    type Reply = Result<(), PutPointerError>;
+
+    fn peer_selection(&self) -> PeerSelection {
+        PeerSelection::InBucket { bucket: self.bucket, max_count: REPLICATION }
+    }
+
+    fn pow(&self) -> PowConfig {
+        PowConfig::High
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_pointers_verifier() {
+        let verifier = CountPointersVerifier(MedianVerifier { tolerance: MEDIAN_TOLERANCE });
+
+        let (results, reply) = verifier.verify(vec![(Id([1; 32]), Ok(100)), (Id([2; 32]), Err(BlockError::Expired)), (Id([3; 32]), Ok(10))]);
+        assert_eq!(reply, Some(Ok(100)));
+        assert_eq!(results.len(), 2, "errors are neither verified nor rejected");
+
+        let (results, reply) = verifier.verify(vec![(Id([1; 32]), Err(BlockError::Expired))]);
+        assert_eq!(reply, Some(Err(BlockError::Expired)));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_mask_verifier() {
+        let coarse = Mask::new_hex_mask(MAX_POINTERS_IN_BLOCK, 0);
+        let fine = Mask::new_hex_mask(MAX_POINTERS_IN_BLOCK, MAX_POINTERS_IN_BLOCK * 16);
 
-    fn route_config(&self) -> RouteConfig {
-        RouteConfig { peers: PeerSelection::InBucket { bucket: self.bucket.clone(), max_count: MAX_POINTERS_IN_BLOCK }, pow: PowConfig::High }
+        let (results, reply) = MaskVerifier.verify(vec![(Id([1; 32]), Ok(fine.clone())), (Id([2; 32]), Ok(coarse)), (Id([3; 32]), Ok(fine.clone()))]);
+        assert_eq!(reply, Some(Ok(fine)));
+        assert_eq!(results, vec![(Id([1; 32]), true), (Id([3; 32]), true)]);
     }
 }
@@ -1,86 +1,734 @@
-use std::{collections::{BTreeMap, HashMap, HashSet}, path::PathBuf};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::ErrorKind, path::PathBuf, time::Duration};
 
+use bytes::Bytes;
 use emittio_crypto::id::{Id, Mask};
-use emittio_network::types::Handler;
-use tokio::io;
+use emittio_dht::service::blob_path;
+use emittio_network::{actor::NetworkActorHandle, error::ServiceError, query::Queryable, service::{ChunkStream, IncomingQuery, NetworkHandler, Service, StreamHandler, dispatch, dispatch_stream}};
+use tokio::{fs, sync::mpsc, task::JoinHandle};
+use tokio_stream::Stream;
 
-use crate::{query::{CountPointers, GetPointers, PutPointer}, types::{BlockTime, MAX_POINTERS_IN_BLOCK, Pointer}, utils::{block_time, current_time}};
+use crate::{disk::{self, Log, LogRecord}, error::{BlockError, PutPointerError, StorageError}, query::{CountPointers, GetDigests, GetMask, GetPointers, PutPointer}, sync::{LocalBlock, Merge, SyncCursors, sync_round}, types::{BLOCK_DURATION_IN_SECS, BlockDigest, BlockTime, BucketDigest, MAX_POINTERS_IN_BLOCK, Pointer}, utils::{block_time, current_time}};
+
+/// Pointers per chunk of a streamed `GetPointers` reply
+const POINTERS_PER_CHUNK: usize = 256;
+/// Number of log records after which changed buckets are written to their files and the log is cleared
+const CHECKPOINT_RECORDS: usize = 1024;
+/// How often expired blocks are dropped and a synchronization round with replicas is started
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a node keeps blocks unless configured otherwise
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Default)]
+struct Bucket {
+    pointers: Vec<Pointer>,
+    /// Ids of `pointers`
+    ids: HashSet<Id>,
+    digest: BucketDigest,
+}
+
+impl Bucket {
+    fn new(pointers: Vec<Pointer>) -> Self {
+        let mut bucket = Self::default();
+
+        for pointer in pointers {
+            bucket.push(pointer);
+        }
+
+        bucket
+    }
+
+    fn push(&mut self, pointer: Pointer) {
+        let id = pointer.id();
+
+        self.digest.add(&id);
+        self.ids.insert(id);
+        self.pointers.push(pointer);
+    }
+}
 
 struct Block {
     count: u64,
-    buckets: HashMap<Id, Vec<Pointer>>,
+    buckets: HashMap<Id, Bucket>,
     changed: HashSet<Id>,
     mask: Mask,
 }
 
+/// Synchronization with other replicas
+struct Replication {
+    network: NetworkActorHandle,
+    merges_tx: mpsc::Sender<Merge>,
+    merges_rx: mpsc::Receiver<Merge>,
+    round: Option<JoinHandle<()>>,
+    cursors: SyncCursors,
+}
+
+/// Pointers by block and bucket. Every stored pointer is appended to a log before it is acknowledged,
+/// and changed buckets are written to their own files at checkpoints, see [`PointerStorage::checkpoint`]
 pub struct PointerStorage {
     dir: PathBuf,
     blocks: BTreeMap<BlockTime, Block>,
+    log: Log,
+    /// Number of blocks kept before the current one
+    retention: u64,
+    replication: Option<Replication>,
+    /// Directory of the local DHT store. When set, only pointers to content stored there are accepted
+    cids: Option<PathBuf>,
 }
 
 impl PointerStorage {
-    fn get_bucket(&self, time: &BlockTime, bucket: &Bucket) -> Option<&Vec<Pointer>> {
+    /// Loads the blocks stored in `dir` and replays the log on top of them. Blocks older than `retention` are dropped
+    pub async fn open(dir: PathBuf, retention: Duration) -> Result<Self, StorageError> {
+        let blocks = disk::read_blocks(&dir).await?
+            .into_iter()
+            .map(|stored| (stored.time, Block {
+                count: stored.buckets.values().map(|bucket| bucket.len() as u64).sum(),
+                buckets: stored.buckets.into_iter().map(|(id, pointers)| (id, Bucket::new(pointers))).collect(),
+                changed: HashSet::new(),
+                mask: stored.mask,
+            }))
+            .collect();
+
+        let (log, records) = Log::open(&dir).await?;
+        let retention = retention.as_secs().div_ceil(BLOCK_DURATION_IN_SECS);
+        let mut storage = Self { dir, blocks, log, retention, replication: None, cids: None };
+
+        for record in records {
+            // The log may outlive blocks that were swept
+            if storage.is_expired(record.time) {
+                continue;
+            }
+
+            let block = storage.block_mut(record.time).await?;
+            let bucket = block.buckets.entry(record.bucket).or_default();
+
+            if record.index as usize >= bucket.pointers.len() {
+                bucket.push(record.pointer);
+                block.count += 1;
+                block.changed.insert(record.bucket);
+            }
+        }
+
+        storage.sweep().await?;
+
+        Ok(storage)
+    }
+
+    /// Takes part in synchronization with other replicas of the buckets through `network`. Rounds run in the background,
+    /// pointers they fetch are stored on the next maintenance
+    pub fn replicate(&mut self, network: NetworkActorHandle) {
+        let (merges_tx, merges_rx) = mpsc::channel(POINTERS_PER_CHUNK);
+
+        self.replication = Some(Replication { network, merges_tx, merges_rx, round: None, cursors: HashMap::new() });
+    }
+
+    /// Accepts only pointers whose content is stored in the DHT store at `dht_dir`
+    pub fn require_cids(&mut self, dht_dir: PathBuf) {
+        self.cids = Some(dht_dir);
+    }
+
+    /// Whether the content of a pointer is stored locally. Always true unless `require_cids` was called
+    async fn has_cid(&self, cid: &Id) -> bool {
+        match &self.cids {
+            Some(dir) => fs::try_exists(blob_path(dir, cid)).await.unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// Whether the block is older than the retention window
+    pub fn is_expired(&self, time: BlockTime) -> bool {
+        time.saturating_add(self.retention) < block_time(current_time())
+    }
+
+    /// Drops expired blocks from memory and disk
+    pub async fn sweep(&mut self) -> Result<(), StorageError> {
+        let expired: Vec<BlockTime> = self.blocks.keys()
+            .copied()
+            .take_while(|time| self.is_expired(*time))
+            .collect();
+
+        for time in expired {
+            self.blocks.remove(&time);
+
+            match fs::remove_dir_all(disk::block_dir(&self.dir, time)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes changed buckets to their files and clears the log
+    pub async fn checkpoint(&mut self) -> Result<(), StorageError> {
+        for (time, block) in self.blocks.iter_mut() {
+            for bucket in block.changed.iter() {
+                disk::write_atomic(&disk::bucket_path(&self.dir, *time, bucket), &block.buckets[bucket].pointers).await?;
+            }
+
+            block.changed.clear();
+        }
+
+        self.log.clear().await
+    }
+
+    /// Mask of the block. Blocks that don't exist yet get one sized by the pointer count of the previous block
+    pub fn mask(&self, time: BlockTime) -> Mask {
+        if let Some(block) = self.blocks.get(&time) {
+            return block.mask.clone();
+        }
+
+        let previous_pointer_count = self.blocks.range(..time)
+            .next_back()
+            .map(|(_, b)| b.count)
+            .unwrap_or(0);
+
+        Mask::new_hex_mask(MAX_POINTERS_IN_BLOCK, previous_pointer_count)
+    }
+
+    /// Gets the block, creating it if needed
+    async fn block_mut(&mut self, time: BlockTime) -> Result<&mut Block, StorageError> {
+        if !self.blocks.contains_key(&time) {
+            self.create_block(time, self.mask(time)).await?;
+        }
+
+        Ok(self.blocks.get_mut(&time).expect("block exists"))
+    }
+
+    async fn create_block(&mut self, time: BlockTime, mask: Mask) -> Result<(), StorageError> {
+        fs::create_dir_all(disk::block_dir(&self.dir, time)).await?;
+        disk::write_atomic(&disk::mask_path(&self.dir, time), &mask).await?;
+
+        self.blocks.insert(time, Block { count: 0, buckets: HashMap::new(), changed: HashSet::new(), mask });
+
+        Ok(())
+    }
+
+    /// Gets a bucket normalizing its id with the block's mask
+    fn get_bucket(&self, time: &BlockTime, bucket: &Id) -> Option<&Bucket> {
         self.blocks.get(time)
-            .map(|b| b.buckets.get(bucket))
-            .flatten()
+            .and_then(|b| b.buckets.get(&bucket.bucket(&b.mask)))
+    }
+
+    /// Logs the records and adds their pointers to the buckets. Blocks of the records must exist
+    async fn store(&mut self, records: Vec<LogRecord>) -> Result<(), StorageError> {
+        // A pointer that didn't make it to the log would be lost on restart
+        self.log.append(&records).await?;
+
+        for record in records {
+            let block = self.blocks.get_mut(&record.time).expect("block exists");
+
+            block.buckets.entry(record.bucket).or_default().push(record.pointer);
+            block.count += 1;
+            block.changed.insert(record.bucket);
+        }
+
+        if self.log.len() >= CHECKPOINT_RECORDS {
+            self.checkpoint().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores the pointers of a replica's bucket that are missing locally. They are validated like the ones of `PutPointer`
+    async fn merge(&mut self, merge: Merge) -> Result<(), StorageError> {
+        // Blocks get their masks from local counts, a replica can't impose one. Nor can it open blocks ahead of the clock
+        if self.is_expired(merge.time) || merge.time > block_time(current_time()) || self.mask(merge.time) != merge.mask || merge.bucket.bucket(&merge.mask) != merge.bucket {
+            return Ok(());
+        }
+
+        let mut known = Vec::new();
+
+        for pointer in merge.pointers {
+            if pointer.tag().is_valid() && self.has_cid(pointer.cid()).await {
+                known.push(pointer);
+            }
+        }
+
+        if !self.blocks.contains_key(&merge.time) {
+            self.create_block(merge.time, merge.mask).await?;
+        }
+
+        let bucket = self.blocks[&merge.time].buckets.get(&merge.bucket);
+        let mut index = bucket.map_or(0, |b| b.pointers.len()) as u64;
+        let mut ids = HashSet::new();
+        let mut records = Vec::new();
+
+        for pointer in known {
+            let id = pointer.id();
+
+            if bucket.is_some_and(|b| b.ids.contains(&id)) || !ids.insert(id) {
+                continue;
+            }
+
+            if index >= MAX_POINTERS_IN_BLOCK {
+                break;
+            }
+
+            records.push(LogRecord { time: merge.time, bucket: merge.bucket, index, pointer });
+            index += 1;
+        }
+
+        self.store(records).await
+    }
+
+    /// Stores pointers fetched by the last synchronization round
+    async fn apply_merges(&mut self) {
+        let Some(replication) = &mut self.replication else {
+            return;
+        };
+
+        let mut merges = Vec::new();
+
+        while let Ok(merge) = replication.merges_rx.try_recv() {
+            // Pointers the merge rejects are not fetched from the same replica again
+            replication.cursors.insert((merge.time, merge.bucket, merge.peer_id), merge.cursor);
+            merges.push(merge);
+        }
+
+        for merge in merges {
+            self.merge(merge).await.ok();
+        }
+    }
+
+    /// Starts a synchronization round of all retained blocks unless one is running
+    fn start_sync_round(&mut self) {
+        let to = block_time(current_time());
+        let from = to.saturating_sub(self.retention);
+
+        let Some(replication) = &mut self.replication else {
+            return;
+        };
+
+        if replication.round.as_ref().is_some_and(|round| !round.is_finished()) {
+            return;
+        }
+
+        replication.cursors.retain(|(time, _, _), _| *time >= from);
+
+        let local = self.blocks.range(from..)
+            .map(|(time, block)| (*time, LocalBlock {
+                mask: block.mask.clone(),
+                digests: block.buckets.iter().map(|(id, bucket)| (*id, bucket.digest)).collect(),
+            }))
+            .collect();
+
+        let network = replication.network.clone();
+        let merges = replication.merges_tx.clone();
+        let cursors = replication.cursors.clone();
+
+        replication.round = Some(tokio::spawn(async move {
+            sync_round(network, local, cursors, from, to, merges).await.ok();
+        }));
+    }
+}
+
+impl Service for PointerStorage {
+    async fn handle(&mut self, incoming: IncomingQuery) -> Result<Bytes, ServiceError> {
+        match incoming.query.method_id {
+            CountPointers::METHOD_ID => dispatch::<CountPointers, _>(self, &incoming).await,
+            GetPointers::METHOD_ID => dispatch::<GetPointers, _>(self, &incoming).await,
+            PutPointer::METHOD_ID => dispatch::<PutPointer, _>(self, &incoming).await,
+            GetMask::METHOD_ID => dispatch::<GetMask, _>(self, &incoming).await,
+            method_id => Err(ServiceError::UnknownMethod(method_id)),
+        }
+    }
+
+    async fn handle_stream(&mut self, incoming: IncomingQuery) -> Result<ChunkStream, ServiceError> {
+        match incoming.query.method_id {
+            GetPointers::METHOD_ID => dispatch_stream::<GetPointers, _>(self, &incoming).await,
+            GetDigests::METHOD_ID => dispatch_stream::<GetDigests, _>(self, &incoming).await,
+            method_id => Err(ServiceError::UnknownMethod(method_id)),
+        }
+    }
+
+    fn maintenance_interval(&self) -> Option<Duration> {
+        Some(MAINTENANCE_INTERVAL)
+    }
+
+    async fn maintain(&mut self) {
+        self.sweep().await.ok();
+        self.apply_merges().await;
+        self.start_sync_round();
+    }
+}
+
+impl NetworkHandler<CountPointers> for PointerStorage {
+    async fn handle(&mut self, query: CountPointers) -> Result<u64, BlockError> {
+        if self.is_expired(query.time) {
+            return Err(BlockError::Expired);
+        }
+
+        Ok(self.blocks.get(&query.time).map(|b| b.count).unwrap_or(0))
     }
 }
 
-impl Handler<CountPointers> for PointerStorage {
-    async fn handle(&self, query: CountPointers) -> u64 {
-        self.blocks.get(&query.time).map(|b| b.count).unwrap_or(0)
+impl NetworkHandler<GetMask> for PointerStorage {
+    async fn handle(&mut self, query: GetMask) -> Result<Mask, BlockError> {
+        if self.is_expired(query.time) {
+            return Err(BlockError::Expired);
+        }
+
+        Ok(self.mask(query.time))
     }
 }
 
-impl Handler<GetPointers> for PointerStorage {
-    async fn handle(&self, query: GetPointers) -> Vec<Pointer> {
+impl NetworkHandler<GetPointers> for PointerStorage {
+    async fn handle(&mut self, query: GetPointers) -> Result<Vec<Pointer>, BlockError> {
+        if self.is_expired(query.time) {
+            return Err(BlockError::Expired);
+        }
+
         let Some(bucket) = self.get_bucket(&query.time, &query.bucket) else {
-            return Vec::new()
+            return Ok(Vec::new())
         };
 
-        let from = bucket.len().min(query.cursor as usize);
-        let to = bucket.len().min(from + query.count as usize);
+        let pointers = &bucket.pointers;
+        let from = pointers.len().min(query.cursor as usize);
+        let to = pointers.len().min(from.saturating_add(query.count as usize));
 
-        Vec::from(&bucket[from..to])
+        Ok(Vec::from(&pointers[from..to]))
     }
 }
 
-impl Handler<PutPointer> for PointerStorage {
-    async fn handle(&mut self, query: PutPointer) {
-        let block_time = block_time(current_time());
-
-        let block = match self.blocks.get_mut(&block_time) {
-            Some(block) => block,
-            None => {
-                let previous_pointer_count = self.blocks.last_entry()
-                    .map(|e| e.get())
-                    .map(|b| b.count)
-                    .unwrap_or(0);
-
-                let block = Block {
-                    count: 0,
-                    buckets: HashMap::new(),
-                    changed: HashSet::new(),
-                    mask: Mask::new_hex_mask(MAX_POINTERS_IN_BLOCK, previous_pointer_count),
-                };
-
-                self.blocks.insert(block_time, block);
-                &mut self.blocks[block_time]
-            },
+impl StreamHandler<GetPointers> for PointerStorage {
+    /// Streams the same range as `GetPointers` in pages of `POINTERS_PER_CHUNK`. An expired block is a single error chunk
+    async fn stream(&mut self, query: GetPointers) -> impl Stream<Item = Result<Vec<Pointer>, BlockError>> + Send + 'static {
+        let pages = match NetworkHandler::<GetPointers>::handle(self, query).await {
+            Ok(pointers) => pointers.chunks(POINTERS_PER_CHUNK).map(|page| Ok(Vec::from(page))).collect(),
+            Err(err) => vec![Err(err)],
         };
 
-        let bucket_key = query.bucket.bucket(&block.mask); // Normalize bucket to avoid 
+        tokio_stream::iter(pages)
+    }
+}
 
-        let bucket = match block.buckets.get_mut(&bucket_key) {
-            Some(bucket) => bucket,
-            None => {
-                block.buckets.insert(bucket_key, Vec::new());
-                &mut block.buckets[bucket_key]
-            },
+impl StreamHandler<GetDigests> for PointerStorage {
+    async fn stream(&mut self, query: GetDigests) -> impl Stream<Item = BlockDigest> + Send + 'static {
+        let digests: Vec<BlockDigest> = match query.from <= query.to {
+            true => self.blocks.range(query.from..=query.to)
+                .filter(|(time, _)| !self.is_expired(**time))
+                .map(|(time, block)| BlockDigest {
+                    time: *time,
+                    mask: block.mask.clone(),
+                    buckets: block.buckets.iter().map(|(id, bucket)| (*id, bucket.digest)).collect(),
+                })
+                .collect(),
+            false => Vec::new(),
         };
 
-        bucket.push(query.pointer);
-        block.count += 1;
-        block.changed.insert(bucket_key);
+        tokio_stream::iter(digests)
+    }
+}
+
+impl NetworkHandler<PutPointer> for PointerStorage {
+    async fn handle(&mut self, query: PutPointer) -> Result<(), PutPointerError> {
+        let time = block_time(current_time());
+
+        if !query.pointer.tag().is_valid() {
+            return Err(PutPointerError::InvalidTag);
+        }
+
+        if !self.has_cid(query.pointer.cid()).await {
+            return Err(PutPointerError::UnknownCid);
+        }
+
+        let block = self.block_mut(time).await.map_err(|_| PutPointerError::Internal)?;
+
+        let bucket_id = query.bucket.bucket(&block.mask); // Normalize bucket to avoid 
+        let bucket = block.buckets.get(&bucket_id);
+        let index = bucket.map_or(0, |b| b.pointers.len()) as u64;
+
+        if bucket.is_some_and(|b| b.ids.contains(&query.pointer.id())) {
+            return Err(PutPointerError::Duplicate);
+        }
+
+        if index >= MAX_POINTERS_IN_BLOCK {
+            return Err(PutPointerError::BucketFull);
+        }
+
+        self.store(vec![LogRecord { time, bucket: bucket_id, index, pointer: query.pointer }]).await
+            .map_err(|_| PutPointerError::Internal)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use emittio_crypto::{derivable::Derivable, tag::TagVerifier};
+    use emittio_network::sim::SimNetwork;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::POINTER_SERVICE_ID;
+
+    use super::*;
+
+    fn pointer(i: u8) -> Pointer {
+        Pointer::new(TagVerifier::derive([1; 32]).address().generate_tag(), Id([i; 32]))
+    }
+
+    async fn put(storage: &mut PointerStorage, i: u8) {
+        NetworkHandler::<PutPointer>::handle(storage, PutPointer { bucket: Id::default(), pointer: pointer(i) }).await.expect("put failed");
+    }
+
+    async fn cids(storage: &mut PointerStorage) -> Vec<Id> {
+        let time = *storage.blocks.last_key_value().expect("no blocks").0;
+        let query = GetPointers { time, bucket: Id::default(), cursor: 0, count: u64::MAX };
+
+        NetworkHandler::<GetPointers>::handle(storage, query).await
+            .expect("block expired")
+            .iter()
+            .map(|pointer| *pointer.cid())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        put(&mut storage, 1).await;
+        put(&mut storage, 2).await;
+        storage.checkpoint().await.expect("checkpoint failed");
+        put(&mut storage, 3).await;
+        drop(storage);
+
+        // Two pointers come from the bucket file, the last one from the log
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        assert_eq!(cids(&mut storage).await, vec![Id([1; 32]), Id([2; 32]), Id([3; 32])]);
+    }
+
+    #[tokio::test]
+    async fn test_crash_during_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("pointers.log");
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        put(&mut storage, 1).await;
+        put(&mut storage, 2).await;
+
+        // The bucket file is written but the log is not cleared
+        let records = fs::read(&log).await.unwrap();
+        storage.checkpoint().await.expect("checkpoint failed");
+        drop(storage);
+        fs::write(&log, records).await.unwrap();
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        assert_eq!(cids(&mut storage).await, vec![Id([1; 32]), Id([2; 32])], "replayed records should not be duplicated");
+    }
+
+    #[tokio::test]
+    async fn test_torn_log() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        put(&mut storage, 1).await;
+        drop(storage);
+
+        // A record cut short by a crash
+        let mut log = fs::OpenOptions::new().append(true).open(dir.path().join("pointers.log")).await.unwrap();
+        log.write_all(&[200, 0, 0, 0, 1, 2, 3]).await.unwrap();
+        drop(log);
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        put(&mut storage, 2).await;
+        drop(storage);
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        assert_eq!(cids(&mut storage).await, vec![Id([1; 32]), Id([2; 32])]);
+    }
+
+    #[tokio::test]
+    async fn test_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = block_time(current_time());
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        let old = now - storage.retention - 1;
+
+        storage.block_mut(old).await.expect("block failed");
+        storage.log.append(&[LogRecord { time: old, bucket: Id::default(), index: 0, pointer: pointer(1) }]).await.expect("append failed");
+
+        storage.sweep().await.expect("sweep failed");
+        assert!(!disk::block_dir(dir.path(), old).exists());
+
+        let count = |time| CountPointers { time };
+        assert_eq!(NetworkHandler::<CountPointers>::handle(&mut storage, count(old)).await, Err(BlockError::Expired));
+        assert_eq!(NetworkHandler::<CountPointers>::handle(&mut storage, count(now)).await, Ok(0));
+        drop(storage);
+
+        // The log record doesn't bring the block back
+        let storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        assert!(storage.blocks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bucket_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = block_time(current_time());
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+
+        // The previous block was crowded, so the current one gets a finer mask
+        storage.block_mut(now - 1).await.expect("block failed").count = MAX_POINTERS_IN_BLOCK * 16;
+
+        let mask = NetworkHandler::<GetMask>::handle(&mut storage, GetMask { time: now }).await.expect("block expired");
+        assert_eq!(mask, Mask::new_hex_mask(MAX_POINTERS_IN_BLOCK, MAX_POINTERS_IN_BLOCK * 16));
+        assert_ne!(mask, storage.mask(now - 1));
+
+        let address = Id([0xab; 32]);
+        let bucket = address.bucket(&mask);
+
+        let pointers = vec![pointer(0); MAX_POINTERS_IN_BLOCK as usize - 1];
+        storage.block_mut(now).await.expect("block failed").buckets.insert(bucket, Bucket::new(pointers));
+
+        let put = |i| PutPointer { bucket: address, pointer: pointer(i) };
+        assert_eq!(NetworkHandler::<PutPointer>::handle(&mut storage, put(1)).await, Ok(()));
+        assert_eq!(NetworkHandler::<PutPointer>::handle(&mut storage, put(2)).await, Err(PutPointerError::BucketFull));
+
+        // Reads normalize the bucket with the same mask
+        let query = GetPointers { time: now, bucket: address, cursor: MAX_POINTERS_IN_BLOCK - 1, count: 10 };
+        let pointers = NetworkHandler::<GetPointers>::handle(&mut storage, query).await.expect("block expired");
+        assert_eq!(pointers.iter().map(|p| *p.cid()).collect::<Vec<_>>(), vec![Id([1; 32])]);
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let sim = SimNetwork::new(0);
+
+        let (node, peer) = sim.spawn_node("node");
+        let dir = tempfile::tempdir().unwrap();
+        let mut replica = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+
+        let pointers: Vec<Pointer> = (1..=3).map(pointer).collect();
+
+        for pointer in pointers.iter().cloned() {
+            NetworkHandler::<PutPointer>::handle(&mut replica, PutPointer { bucket: Id::default(), pointer }).await.expect("put failed");
+        }
+
+        node.register(POINTER_SERVICE_ID, replica).await.expect("register failed");
+
+        // The local storage missed the last two pointers during downtime
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        NetworkHandler::<PutPointer>::handle(&mut storage, PutPointer { bucket: Id::default(), pointer: pointers[0].clone() }).await.expect("put failed");
+        storage.replicate(client);
+
+        storage.maintain().await;
+        let round = storage.replication.as_mut().and_then(|r| r.round.take()).expect("round not started");
+        round.await.expect("round failed");
+        storage.maintain().await;
+
+        assert_eq!(cids(&mut storage).await, vec![Id([1; 32]), Id([2; 32]), Id([3; 32])]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_cursor() {
+        let sim = SimNetwork::new(0);
+
+        let (node, peer) = sim.spawn_node("node");
+        let dir = tempfile::tempdir().unwrap();
+        let mut replica = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+
+        for i in 1..=3 {
+            put(&mut replica, i).await;
+        }
+
+        node.register(POINTER_SERVICE_ID, replica).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer.clone()).await.expect("add peer failed");
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        storage.replicate(client);
+
+        // An earlier round fetched the first two pointers of the replica's bucket
+        let time = block_time(current_time());
+        let replication = storage.replication.as_mut().expect("not replicating");
+        replication.cursors.insert((time, Id::default(), peer.id), 2);
+
+        storage.maintain().await;
+        let round = storage.replication.as_mut().and_then(|r| r.round.take()).expect("round not started");
+        round.await.expect("round failed");
+        storage.maintain().await;
+
+        assert_eq!(cids(&mut storage).await, vec![Id([3; 32])]);
+        assert_eq!(storage.replication.as_ref().expect("not replicating").cursors[&(time, Id::default(), peer.id)], 3);
+    }
+
+    #[tokio::test]
+    async fn test_sync_unknown_cids() {
+        let sim = SimNetwork::new(0);
+
+        let (node, peer) = sim.spawn_node("node");
+        let dir = tempfile::tempdir().unwrap();
+        let mut replica = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+
+        for i in 1..=3 {
+            NetworkHandler::<PutPointer>::handle(&mut replica, PutPointer { bucket: Id::default(), pointer: pointer(i) }).await.expect("put failed");
+        }
+
+        node.register(POINTER_SERVICE_ID, replica).await.expect("register failed");
+
+        let client = sim.spawn_client("client");
+        client.add_peer(peer).await.expect("add peer failed");
+
+        // Only the content of the second pointer is stored locally
+        let dir = tempfile::tempdir().unwrap();
+        let dht_dir = tempfile::tempdir().unwrap();
+
+        let path = blob_path(dht_dir.path(), &Id([2; 32]));
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(path, b"content").await.unwrap();
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        storage.require_cids(dht_dir.path().into());
+        storage.replicate(client);
+
+        storage.maintain().await;
+        let round = storage.replication.as_mut().and_then(|r| r.round.take()).expect("round not started");
+        round.await.expect("round failed");
+        storage.maintain().await;
+
+        assert_eq!(cids(&mut storage).await, vec![Id([2; 32])]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_future_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+
+        let time = block_time(current_time()) + 1;
+        let merge = Merge { time, mask: storage.mask(time), bucket: Id::default(), pointers: vec![pointer(1)], peer_id: Id::default(), cursor: 1 };
+
+        storage.merge(merge).await.expect("merge failed");
+        assert!(storage.blocks.is_empty(), "future block should not be created");
+    }
+
+    #[tokio::test]
+    async fn test_put_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let dht_dir = tempfile::tempdir().unwrap();
+
+        let mut storage = PointerStorage::open(dir.path().into(), DEFAULT_RETENTION).await.expect("open failed");
+        storage.require_cids(dht_dir.path().into());
+
+        let stored = Pointer::new(pointer(0).tag().clone(), Id::hash_bytes(b"content"));
+        let path = blob_path(dht_dir.path(), stored.cid());
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(path, b"content").await.unwrap();
+
+        let put = |pointer| PutPointer { bucket: Id::default(), pointer };
+        assert_eq!(NetworkHandler::<PutPointer>::handle(&mut storage, put(stored.clone())).await, Ok(()));
+        assert_eq!(NetworkHandler::<PutPointer>::handle(&mut storage, put(stored.clone())).await, Err(PutPointerError::Duplicate));
+        assert_eq!(NetworkHandler::<PutPointer>::handle(&mut storage, put(pointer(1))).await, Err(PutPointerError::UnknownCid));
+
+        // Both points are the identity
+        let identity = postcard::from_bytes(&[0; 64]).unwrap();
+        let invalid = Pointer::new(identity, *stored.cid());
+        assert_eq!(NetworkHandler::<PutPointer>::handle(&mut storage, put(invalid)).await, Err(PutPointerError::InvalidTag));
+    }
+}
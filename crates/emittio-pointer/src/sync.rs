@@ -0,0 +1,110 @@
+use std::{collections::HashMap, pin::pin};
+
+use emittio_crypto::id::{Id, Mask};
+use emittio_network::{actor::NetworkActorHandle, error::NetworkError, peer::{Peer, PeerId}, query::Queryable};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+use crate::{query::{GetDigests, GetPointers}, types::{BlockTime, BucketDigest, Pointer}};
+
+/// Most pointers fetched from a single replica in a round, so one peer can't fill the local buckets by itself
+pub const SYNC_POINTERS_PER_PEER: usize = 4096;
+
+/// How far the buckets of replicas were fetched, by block, bucket and replica. Buckets only grow,
+/// so a round continues where the previous one stopped instead of fetching the same first pointers again
+pub type SyncCursors = HashMap<(BlockTime, Id, PeerId), u64>;
+
+/// Pointers of a bucket fetched from a replica
+pub struct Merge {
+    pub time: BlockTime,
+    /// Mask of the replica's block. Buckets can only be merged into a block with the same mask
+    pub mask: Mask,
+    pub bucket: Id,
+    pub pointers: Vec<Pointer>,
+    pub peer_id: PeerId,
+    /// Position in the replica's bucket after `pointers`
+    pub cursor: u64,
+}
+
+/// Digests of a local block taken when a round starts
+pub struct LocalBlock {
+    pub mask: Mask,
+    pub digests: HashMap<Id, BucketDigest>,
+}
+
+/// Compares the digests of blocks `from..=to` with random replicas and fetches the buckets that differ.
+/// Only buckets the node is responsible for are compared, the ones its id is in, and at most `SYNC_POINTERS_PER_PEER` pointers are fetched from every replica.
+/// Buckets are fetched from the `cursors` reached by previous rounds. Fetched pointers are sent to `merges`
+pub async fn sync_round(network: NetworkActorHandle, local: HashMap<BlockTime, LocalBlock>, cursors: SyncCursors, from: BlockTime, to: BlockTime, merges: mpsc::Sender<Merge>) -> Result<(), NetworkError> {
+    let local_id = network.local_id().await?;
+    let query = GetDigests { from, to };
+
+    for peer in network.select(query.peer_selection()).await? {
+        // Other replicas may still have the pointers of a failed one
+        let Ok(digests) = query.query_stream(&network, peer.clone()).await else {
+            continue;
+        };
+        let mut digests = pin!(digests);
+        let mut budget = SYNC_POINTERS_PER_PEER;
+
+        'digests: while let Some(Ok(block)) = digests.next().await {
+            // A replica can't make us create blocks outside the round
+            if !(from..=to).contains(&block.time) {
+                continue;
+            }
+
+            let local_block = local.get(&block.time);
+
+            // Buckets of blocks with different masks hold different pointers
+            if local_block.is_some_and(|b| b.mask != block.mask) {
+                continue;
+            }
+
+            for (bucket, digest) in block.buckets {
+                // Buckets are normalized with the mask, others can't be queried
+                if local_id & bucket != bucket || bucket.bucket(&block.mask) != bucket || local_block.and_then(|b| b.digests.get(&bucket)) == Some(&digest) {
+                    continue;
+                }
+
+                let cursor = cursors.get(&(block.time, bucket, peer.id)).copied().unwrap_or(0);
+                let pointers = fetch(&network, peer.clone(), block.time, bucket, cursor, budget).await;
+                budget -= pointers.len();
+
+                if pointers.is_empty() {
+                    continue;
+                }
+
+                let merge = Merge { time: block.time, mask: block.mask.clone(), bucket, peer_id: peer.id, cursor: cursor + pointers.len() as u64, pointers };
+
+                if merges.send(merge).await.is_err() {
+                    return Ok(()); // The storage is gone
+                }
+
+                if budget == 0 {
+                    break 'digests;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Up to `limit` pointers of the bucket the peer has, starting at `cursor`
+async fn fetch(network: &NetworkActorHandle, peer: Peer, time: BlockTime, bucket: Id, cursor: u64, limit: usize) -> Vec<Pointer> {
+    let query = GetPointers { time, bucket, cursor, count: limit as u64 };
+
+    let Ok(pages) = query.query_stream(network, peer).await else {
+        return Vec::new();
+    };
+
+    let mut pages = pin!(pages);
+    let mut pointers = Vec::new();
+
+    while pointers.len() < limit && let Some(Ok(Ok(page))) = pages.next().await {
+        pointers.extend(page);
+    }
+
+    pointers.truncate(limit);
+    pointers
+}
@@ -1,4 +1,4 @@
-use emittio_crypto::{id::Id, tag::Tag};
+use emittio_crypto::{id::{Id, Mask}, tag::Tag};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -7,6 +7,52 @@ pub struct Pointer {
     cid: Id,
 }
 
+impl Pointer {
+    pub fn new(tag: Tag, cid: Id) -> Self {
+        Self { tag, cid }
+    }
+
+    pub fn tag(&self) -> &Tag {
+        &self.tag
+    }
+
+    /// Id of the content the pointer refers to
+    pub fn cid(&self) -> &Id {
+        &self.cid
+    }
+
+    /// Hash identifying the pointer
+    pub fn id(&self) -> Id {
+        Id::hash_from(self).expect("pointer is always encodable")
+    }
+}
+
+/// Order-independent summary of the pointers of a bucket. Replicas holding the same pointers have equal digests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketDigest {
+    pub count: u64,
+    /// XOR of the pointer ids
+    pub hash: Id,
+}
+
+impl BucketDigest {
+    pub fn add(&mut self, pointer_id: &Id) {
+        self.count += 1;
+
+        for (byte, other) in self.hash.0.iter_mut().zip(pointer_id.0) {
+            *byte ^= other;
+        }
+    }
+}
+
+/// Digests of all buckets a node holds in a block
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockDigest {
+    pub time: BlockTime,
+    pub mask: Mask,
+    pub buckets: Vec<(Id, BucketDigest)>,
+}
+
 pub type BlockTime = u64;
 pub const BLOCK_DURATION_IN_SECS: u64 = 15 * 60;
 pub const MAX_POINTERS_IN_BLOCK: u64 = 16000;